@@ -0,0 +1,366 @@
+use crate::apu::noise::Noise;
+use crate::apu::pulse::Pulse;
+use crate::apu::wave::Wave;
+use crate::utils::*;
+use crate::CLOCK_SPEED;
+
+pub mod noise;
+pub mod pulse;
+pub mod wave;
+
+/// Native output rate of the sample stream, one stereo frame every 16 M-cycles.
+pub const SAMPLE_RATE: u32 = CLOCK_SPEED / 16;
+const MAX_SAMPLES: usize = SAMPLE_RATE as usize * 2;
+
+// bits that always read back as 1, NR10..NR52
+const READ_MASK: [u8; 23] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+];
+
+pub struct Apu {
+    pub ch1: Pulse,
+    pub ch2: Pulse,
+    pub ch3: Wave,
+    pub ch4: Noise,
+    regs: [u8; 23],
+    power: bool,
+    fs_step: u8,
+    sample_cycles: u16,
+    capacitor: [f32; 2],
+    samples: Vec<f32>,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Self {
+            ch1: Pulse::new(true),
+            ch2: Pulse::new(false),
+            ch3: Wave::new(),
+            ch4: Noise::new(),
+            regs: [0; 23],
+            power: false,
+            fs_step: 0,
+            sample_cycles: 0,
+            capacitor: [0.0; 2],
+            samples: Vec::with_capacity(MAX_SAMPLES),
+        }
+    }
+
+    pub fn boot() -> Self {
+        let mut apu: Apu = Self::new();
+        apu.write_byte(NR52, 0x80);
+        for (addr, b) in [
+            (NR10, 0x80),
+            (NR11, 0xBF),
+            (NR12, 0xF3),
+            (NR14, 0x3F),
+            (NR21, 0x3F),
+            (NR24, 0x3F),
+            (NR30, 0x7F),
+            (NR31, 0xFF),
+            (NR32, 0x9F),
+            (NR34, 0x3F),
+            (NR41, 0xFF),
+            (NR44, 0x3F),
+            (NR50, 0x77),
+            (NR51, 0xF3),
+        ] {
+            apu.write_byte(addr, b);
+        }
+        // the boot chime leaves channel 1 running with its envelope decayed to 0
+        apu.ch1.enabled = true;
+        apu
+    }
+
+    pub fn cycle(&mut self, cycles: u16) {
+        if self.power {
+            let t_cycles: i32 = cycles as i32 * 4;
+            self.ch1.step(t_cycles);
+            self.ch2.step(t_cycles);
+            self.ch3.step(t_cycles);
+            self.ch4.step(t_cycles);
+        }
+        self.sample_cycles += cycles;
+        while self.sample_cycles >= 16 {
+            self.sample_cycles -= 16;
+            self.sample();
+        }
+    }
+
+    /// Clocked by the falling edge of DIV bit 4 (512 Hz).
+    pub fn frame_sequencer(&mut self) {
+        if !self.power {
+            return;
+        }
+        match self.fs_step {
+            0 | 4 => self.clock_length(),
+            2 | 6 => {
+                self.clock_length();
+                self.ch1.clock_sweep();
+            }
+            7 => {
+                self.ch1.envelope.clock();
+                self.ch2.envelope.clock();
+                self.ch4.envelope.clock();
+            }
+            _ => (),
+        }
+        self.fs_step = (self.fs_step + 1) & 7;
+    }
+
+    /// Drains the interleaved stereo samples produced since the last call.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            NR52 => {
+                0x70 | (self.power as u8) << 7
+                    | (self.ch4.enabled as u8) << 3
+                    | (self.ch3.enabled as u8) << 2
+                    | (self.ch2.enabled as u8) << 1
+                    | self.ch1.enabled as u8
+            }
+            NR10..NR52 => {
+                let i: usize = (addr - NR10) as usize;
+                self.regs[i] | READ_MASK[i]
+            }
+            0xFF27..0xFF30 => 0xFF,
+            0xFF30..0xFF40 => self.ch3.read_ram(addr),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write_byte(&mut self, addr: u16, b: u8) {
+        match addr {
+            NR52 => {
+                let power: bool = bit(b, 7);
+                if self.power && !power {
+                    let ram: [u8; 16] = self.ch3.ram;
+                    *self = Self {
+                        samples: std::mem::take(&mut self.samples),
+                        ..Self::new()
+                    };
+                    self.ch3.ram = ram;
+                } else if !self.power && power {
+                    self.fs_step = 0;
+                }
+                self.power = power;
+            }
+            NR10..NR52 => {
+                if !self.power {
+                    return;
+                }
+                let i: usize = (addr - NR10) as usize;
+                self.regs[i] = b;
+                match i {
+                    0..5 => self.ch1.write(i, b, self.fs_step),
+                    5..10 => self.ch2.write(i - 5, b, self.fs_step),
+                    10..15 => self.ch3.write(i - 10, b, self.fs_step),
+                    15..20 => self.ch4.write(i - 15, b, self.fs_step),
+                    _ => (), // NR50/NR51 are only read back when mixing
+                }
+            }
+            0xFF27..0xFF30 => (),
+            0xFF30..0xFF40 => self.ch3.write_ram(addr, b),
+            _ => unreachable!(),
+        }
+    }
+
+    fn clock_length(&mut self) {
+        self.ch1.clock_length();
+        self.ch2.clock_length();
+        self.ch3.clock_length();
+        self.ch4.clock_length();
+    }
+
+    fn sample(&mut self) {
+        if self.samples.len() >= MAX_SAMPLES {
+            return;
+        }
+        let (mut left, mut right) = (0f32, 0f32);
+        if self.power {
+            let nr50: u8 = self.regs[(NR50 - NR10) as usize];
+            let nr51: u8 = self.regs[(NR51 - NR10) as usize];
+            let outputs: [Option<u8>; 4] = [
+                self.ch1.output(),
+                self.ch2.output(),
+                self.ch3.output(),
+                self.ch4.output(),
+            ];
+            for (i, output) in outputs.iter().enumerate() {
+                // each DAC maps 0..15 linearly onto 1.0..-1.0, disabled DACs output nothing
+                if let Some(v) = output {
+                    let analog: f32 = 1.0 - *v as f32 / 7.5;
+                    if bit(nr51, i as u8 + 4) {
+                        left += analog;
+                    }
+                    if bit(nr51, i as u8) {
+                        right += analog;
+                    }
+                }
+            }
+            left *= (((nr50 >> 4) & 7) + 1) as f32 / 32.0;
+            right *= ((nr50 & 7) + 1) as f32 / 32.0;
+        }
+        // high-pass filter standing in for the output capacitor, removes the DAC's DC offset
+        for (i, v) in [left, right].into_iter().enumerate() {
+            let out: f32 = v - self.capacitor[i];
+            self.capacitor[i] = v - out * 0.9973;
+            self.samples.push(out);
+        }
+    }
+}
+
+pub struct Length {
+    counter: u16,
+    max: u16,
+    enabled: bool,
+}
+
+impl Length {
+    pub fn new(max: u16) -> Self {
+        Self {
+            counter: 0,
+            max,
+            enabled: false,
+        }
+    }
+
+    pub fn load(&mut self, v: u8) {
+        self.counter = self.max - v as u16;
+    }
+
+    /// Returns true when the counter expires and the channel should be disabled.
+    pub fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+
+    /// Handles the length enable bit of NRx4, returning true if the write expired the counter.
+    pub fn write_enable(&mut self, enable: bool, fs_step: u8) -> bool {
+        // enabling length while the next sequencer step won't clock it clocks it once extra
+        let extra: bool = !self.enabled && enable && fs_step & 1 == 1;
+        self.enabled = enable;
+        extra && self.clock()
+    }
+
+    pub fn trigger(&mut self, fs_step: u8) {
+        if self.counter == 0 {
+            self.counter = self.max;
+            if self.enabled && fs_step & 1 == 1 {
+                self.counter -= 1;
+            }
+        }
+    }
+}
+
+pub struct Envelope {
+    initial: u8,
+    add: bool,
+    period: u8,
+    timer: u8,
+    pub volume: u8,
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Self {
+            initial: 0,
+            add: false,
+            period: 0,
+            timer: 8,
+            volume: 0,
+        }
+    }
+
+    pub fn write(&mut self, b: u8) {
+        self.initial = b >> 4;
+        self.add = bit(b, 3);
+        self.period = b & 7;
+    }
+
+    pub fn dac(&self) -> bool {
+        self.initial != 0 || self.add
+    }
+
+    pub fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    pub fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        // NRx2 can set a period without a trigger ever having loaded the timer
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.add && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.add && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn power_on_clocks_sequencer() {
+        let mut apu: Apu = Apu::new();
+        apu.write_byte(NR52, 0x80);
+        apu.write_byte(NR10, 0x00);
+        apu.write_byte(NR12, 0xF3);
+        for _ in 0..64 {
+            apu.frame_sequencer();
+        }
+        assert_eq!(apu.read_byte(NR52) & 0x80, 0x80);
+    }
+
+    #[test]
+    fn boot_clocks_sequencer() {
+        let mut apu: Apu = Apu::boot();
+        for _ in 0..64 {
+            apu.frame_sequencer();
+        }
+        assert!(apu.ch1.enabled);
+    }
+
+    #[test]
+    fn envelope_without_trigger() {
+        let mut envelope: Envelope = Envelope::new();
+        envelope.write(0xF1);
+        for _ in 0..16 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.volume, 0);
+        envelope.trigger();
+        envelope.clock();
+        assert_eq!(envelope.volume, 14);
+    }
+}
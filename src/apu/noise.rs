@@ -0,0 +1,105 @@
+use crate::apu::{Envelope, Length};
+use crate::utils::*;
+
+const DIVISORS: [i32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+pub struct Noise {
+    pub enabled: bool,
+    pub envelope: Envelope,
+    length: Length,
+    shift: u8,
+    narrow: bool,
+    divisor: u8,
+    lfsr: u16,
+    timer: i32,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Noise {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            envelope: Envelope::new(),
+            length: Length::new(64),
+            shift: 0,
+            narrow: false,
+            divisor: 0,
+            lfsr: 0x7FFF,
+            timer: 0,
+        }
+    }
+
+    pub fn write(&mut self, reg: usize, b: u8, fs_step: u8) {
+        match reg {
+            0 => (), // NR40 doesn't exist
+            1 => self.length.load(b & 0x3F),
+            2 => {
+                self.envelope.write(b);
+                if !self.envelope.dac() {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.shift = b >> 4;
+                self.narrow = bit(b, 3);
+                self.divisor = b & 7;
+            }
+            4 => {
+                let trigger: bool = bit(b, 7);
+                if self.length.write_enable(bit(b, 6), fs_step) && !trigger {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.enabled = self.envelope.dac();
+                    self.length.trigger(fs_step);
+                    self.envelope.trigger();
+                    self.lfsr = 0x7FFF;
+                    self.timer = self.period();
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn step(&mut self, t_cycles: i32) {
+        // shifts 14 and 15 stop the LFSR from being clocked at all
+        if !self.enabled || self.shift >= 14 {
+            return;
+        }
+        self.timer -= t_cycles;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            let xor: u16 = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (xor << 14);
+            if self.narrow {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (xor << 6);
+            }
+        }
+    }
+
+    pub fn output(&self) -> Option<u8> {
+        if !self.envelope.dac() {
+            return None;
+        }
+        if self.enabled && self.lfsr & 1 == 0 {
+            Some(self.envelope.volume)
+        } else {
+            Some(0)
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn period(&self) -> i32 {
+        DIVISORS[self.divisor as usize] << self.shift
+    }
+}
@@ -0,0 +1,183 @@
+use crate::apu::{Envelope, Length};
+use crate::utils::*;
+
+const DUTY: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+pub struct Pulse {
+    pub enabled: bool,
+    pub envelope: Envelope,
+    sweep: Option<Sweep>,
+    length: Length,
+    duty: u8,
+    phase: u8,
+    freq: u16,
+    timer: i32,
+}
+
+impl Pulse {
+    pub fn new(sweep: bool) -> Self {
+        Self {
+            enabled: false,
+            envelope: Envelope::new(),
+            sweep: sweep.then(Sweep::new),
+            length: Length::new(64),
+            duty: 0,
+            phase: 0,
+            freq: 0,
+            timer: 0,
+        }
+    }
+
+    pub fn write(&mut self, reg: usize, b: u8, fs_step: u8) {
+        match reg {
+            0 => {
+                if let Some(sweep) = self.sweep.as_mut() {
+                    // leaving negate mode after a negated calculation kills the channel
+                    if sweep.negated && sweep.negate && !bit(b, 3) {
+                        self.enabled = false;
+                    }
+                    sweep.write(b);
+                }
+            }
+            1 => {
+                self.duty = b >> 6;
+                self.length.load(b & 0x3F);
+            }
+            2 => {
+                self.envelope.write(b);
+                if !self.envelope.dac() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.freq = (self.freq & 0x700) | b as u16,
+            4 => {
+                self.freq = (self.freq & 0xFF) | ((b as u16 & 7) << 8);
+                let trigger: bool = bit(b, 7);
+                if self.length.write_enable(bit(b, 6), fs_step) && !trigger {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.trigger(fs_step);
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn step(&mut self, t_cycles: i32) {
+        self.timer -= t_cycles;
+        while self.timer <= 0 {
+            self.timer += (2048 - self.freq as i32) * 4;
+            self.phase = (self.phase + 1) & 7;
+        }
+    }
+
+    pub fn output(&self) -> Option<u8> {
+        if !self.envelope.dac() {
+            return None;
+        }
+        if self.enabled {
+            Some(DUTY[self.duty as usize][self.phase as usize] * self.envelope.volume)
+        } else {
+            Some(0)
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_sweep(&mut self) {
+        let Some(sweep) = self.sweep.as_mut() else {
+            return;
+        };
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer > 0 {
+            return;
+        }
+        sweep.reload();
+        if sweep.enabled && sweep.period != 0 {
+            match sweep.calculate() {
+                Some(freq) if sweep.shift != 0 => {
+                    sweep.shadow = freq;
+                    self.freq = freq;
+                    // the new frequency is immediately checked for overflow again
+                    if sweep.calculate().is_none() {
+                        self.enabled = false;
+                    }
+                }
+                Some(_) => (),
+                None => self.enabled = false,
+            }
+        }
+    }
+
+    fn trigger(&mut self, fs_step: u8) {
+        self.enabled = self.envelope.dac();
+        self.length.trigger(fs_step);
+        self.envelope.trigger();
+        self.timer = (2048 - self.freq as i32) * 4;
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.shadow = self.freq;
+            sweep.negated = false;
+            sweep.reload();
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            if sweep.shift != 0 && sweep.calculate().is_none() {
+                self.enabled = false;
+            }
+        }
+    }
+}
+
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    shadow: u16,
+    enabled: bool,
+    negated: bool, // a calculation has used negate mode since the last trigger
+}
+
+impl Sweep {
+    fn new() -> Self {
+        Self {
+            period: 0,
+            negate: false,
+            shift: 0,
+            timer: 8,
+            shadow: 0,
+            enabled: false,
+            negated: false,
+        }
+    }
+
+    fn write(&mut self, b: u8) {
+        self.period = (b >> 4) & 7;
+        self.negate = bit(b, 3);
+        self.shift = b & 7;
+    }
+
+    fn reload(&mut self) {
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    /// Next frequency, or None if it overflows 11 bits.
+    fn calculate(&mut self) -> Option<u16> {
+        let delta: u16 = self.shadow >> self.shift;
+        let freq: u16 = if self.negate {
+            self.negated = true;
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        };
+        (freq <= 0x7FF).then_some(freq)
+    }
+}
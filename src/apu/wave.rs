@@ -0,0 +1,109 @@
+use crate::apu::Length;
+use crate::utils::*;
+
+pub struct Wave {
+    pub enabled: bool,
+    pub ram: [u8; 16],
+    dac: bool,
+    length: Length,
+    volume: u8,
+    position: u8,
+    sample: u8,
+    freq: u16,
+    timer: i32,
+}
+
+impl Default for Wave {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Wave {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            ram: [0; 16],
+            dac: false,
+            length: Length::new(256),
+            volume: 0,
+            position: 0,
+            sample: 0,
+            freq: 0,
+            timer: 0,
+        }
+    }
+
+    pub fn write(&mut self, reg: usize, b: u8, fs_step: u8) {
+        match reg {
+            0 => {
+                self.dac = bit(b, 7);
+                if !self.dac {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(b),
+            2 => self.volume = (b >> 5) & 3,
+            3 => self.freq = (self.freq & 0x700) | b as u16,
+            4 => {
+                self.freq = (self.freq & 0xFF) | ((b as u16 & 7) << 8);
+                let trigger: bool = bit(b, 7);
+                if self.length.write_enable(bit(b, 6), fs_step) && !trigger {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.enabled = self.dac;
+                    self.length.trigger(fs_step);
+                    self.position = 0;
+                    self.timer = (2048 - self.freq as i32) * 2;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn read_ram(&self, addr: u16) -> u8 {
+        self.ram[(addr & 0x0F) as usize]
+    }
+
+    pub fn write_ram(&mut self, addr: u16, b: u8) {
+        self.ram[(addr & 0x0F) as usize] = b;
+    }
+
+    pub fn step(&mut self, t_cycles: i32) {
+        if !self.enabled {
+            return;
+        }
+        self.timer -= t_cycles;
+        while self.timer <= 0 {
+            self.timer += (2048 - self.freq as i32) * 2;
+            self.position = (self.position + 1) & 31;
+            let byte: u8 = self.ram[self.position as usize / 2];
+            // high nibble plays first
+            self.sample = if self.position & 1 == 0 {
+                byte >> 4
+            } else {
+                byte & 0x0F
+            };
+        }
+    }
+
+    pub fn output(&self) -> Option<u8> {
+        if !self.dac {
+            return None;
+        }
+        if !self.enabled {
+            return Some(0);
+        }
+        Some(match self.volume {
+            0 => 0,
+            v => self.sample >> (v - 1),
+        })
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+}
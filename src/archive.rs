@@ -0,0 +1,52 @@
+use crate::mbc::LoadError;
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+
+/// Unpacks a zip or gzip archive, detected by its magic bytes. Anything else is passed
+/// through untouched. Zip archives yield `entry` if given, or else their first `.gb`/`.gbc`.
+pub fn unpack(buf: Vec<u8>, entry: Option<&str>) -> Result<Vec<u8>, LoadError> {
+    if buf.starts_with(ZIP_MAGIC) {
+        unzip(buf, entry)
+    } else if buf.starts_with(GZIP_MAGIC) {
+        let mut rom = Vec::new();
+        flate2::read::GzDecoder::new(&buf[..]).read_to_end(&mut rom)?;
+        Ok(rom)
+    } else {
+        Ok(buf)
+    }
+}
+
+fn unzip(buf: Vec<u8>, entry: Option<&str>) -> Result<Vec<u8>, LoadError> {
+    let mut zip =
+        zip::ZipArchive::new(Cursor::new(buf)).map_err(|e| LoadError::Archive(e.to_string()))?;
+    let index: usize = (0..zip.len())
+        .find(|&i| {
+            let Some(name) = zip.name_for_index(i) else {
+                return false;
+            };
+            match entry {
+                Some(entry) => name == entry || Path::new(name).file_name() == Some(entry.as_ref()),
+                None => Path::new(name)
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .is_some_and(|ext| {
+                        ext.eq_ignore_ascii_case("gb") || ext.eq_ignore_ascii_case("gbc")
+                    }),
+            }
+        })
+        .ok_or_else(|| {
+            LoadError::Archive(match entry {
+                Some(entry) => format!("no entry named {} in zip", entry),
+                None => "no .gb or .gbc entry in zip".to_string(),
+            })
+        })?;
+    let mut file = zip
+        .by_index(index)
+        .map_err(|e| LoadError::Archive(e.to_string()))?;
+    let mut rom = Vec::new();
+    file.read_to_end(&mut rom)?;
+    Ok(rom)
+}
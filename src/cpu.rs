@@ -1,14 +1,18 @@
+use crate::cpu::timing::Timer;
 use crate::cpu::Interrupt::{Joypad, Serial, Stat, TimerInt, VBlank};
 use crate::cpu::ReadWrite::{R, W};
 use crate::cpu::R16::*;
 use crate::cpu::R8::*;
+use crate::mbc::Mbc;
 use crate::mmu::Mmu;
+use crate::model::Model;
 use crate::utils::*;
 use anyhow::{bail, ensure, Result};
 
 pub mod timing;
 
-const DMG_REG: [u8; 8] = [0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D, 0x01, 0xB0];
+// M-cycles the CPU sits paused after STOP while the clock switches speed
+const SPEED_SWITCH_CYCLES: u16 = 2050;
 
 pub struct Cpu {
     rg: Vec<u8>, // B, C, D, E, H, L, A, F
@@ -29,8 +33,14 @@ pub struct Cpu {
     pub h: bool,
     pub c: bool,
     halted: bool,
-    garbage: Vec<u8>,
+    stopped: bool,
+    speed_switch: u16, // M-cycles left in a speed switch
+    half_cycle: bool,  // a double-speed M-cycle not yet passed on to the PPU and APU
     dma_cycles: u8,
+    hdma_cycles: u16, // M-cycles the CPU stays paused for a VRAM DMA
+    spent: u16,       // M-cycles of this step already run by its memory accesses
+    draw: bool,       // V-Blank started during this step
+    timer: Timer,
 }
 
 #[derive(Default)]
@@ -41,7 +51,33 @@ pub struct CpuTickOutput {
 }
 
 impl Cpu {
-    pub fn new(fp: &str) -> Self {
+    pub fn new(cart: Box<dyn Mbc + 'static>) -> Self {
+        Self::with_mmu(Mmu::new(cart))
+    }
+
+    /// Starts from the state the model's boot ROM leaves behind.
+    pub fn boot(cart: Box<dyn Mbc + 'static>, model: Model) -> Self {
+        let mut rv = Self {
+            rg: model.registers().to_vec(),
+            sb: 0,
+            timer: Timer::boot(),
+            ..Self::with_mmu(Mmu::boot(cart))
+        };
+        rv.serial_control(0x7E);
+        rv.iflags.line = 0xE1;
+        rv.read_flags();
+        rv
+    }
+
+    /// Starts at power on, running a real boot ROM from 0x0000 until it unmaps itself.
+    pub fn with_boot_rom(cart: Box<dyn Mbc + 'static>, boot_rom: Vec<u8>) -> Self {
+        Self {
+            pc: 0x0000,
+            ..Self::with_mmu(Mmu::with_boot_rom(cart, boot_rom))
+        }
+    }
+
+    fn with_mmu(mmu: Mmu) -> Self {
         Self {
             rg: vec![0; 8],
             hram: vec![0; 0x80],
@@ -53,7 +89,7 @@ impl Cpu {
             iflags: InterruptFlags::default(),
             ime: false,
             ei: false,
-            mmu: Mmu::new(fp),
+            mmu,
             sp: 0xFFFE,
             pc: 0x0100,
             z: false,
@@ -61,25 +97,31 @@ impl Cpu {
             h: false,
             c: false,
             halted: false,
-            garbage: vec![0; 0x10000],
+            stopped: false,
+            speed_switch: 0,
+            half_cycle: false,
             dma_cycles: 0,
+            hdma_cycles: 0,
+            spent: 0,
+            draw: false,
+            timer: Timer::new(),
         }
     }
 
-    pub fn boot(fp: &str) -> Self {
-        let mut rv = Self {
-            rg: DMG_REG.to_vec(),
-            sb: 0,
-            mmu: Mmu::boot(fp),
-            ..Self::new(fp)
-        };
-        rv.serial_control(0x7E);
-        rv.iflags.line = 0xE1;
-        rv.read_flags();
-        rv
-    }
-
     pub fn cycle(&mut self) -> u16 {
+        if self.stopped {
+            if !self.mmu.joypad.active() {
+                return 1;
+            }
+            self.stopped = false;
+        }
+        if self.speed_switch > 0 {
+            return 1;
+        }
+        if self.hdma_cycles > 0 {
+            self.hdma_cycles -= 1;
+            return 1;
+        }
         let interrupt_cycles: u16 = self.handle_interrupts();
         if self.halted {
             return 1;
@@ -94,14 +136,15 @@ impl Cpu {
         }
     }
 
+    /// Runs one instruction. Each memory access first runs its own M-cycle, so the PPU
+    /// sees writes at the dot they happen; cycles without one run at the end.
     pub fn tick(&mut self) -> CpuTickOutput {
         let mut to: CpuTickOutput = CpuTickOutput::default();
+        self.spent = 0;
         let m_cycles: u16 = self.cycle();
         to.m_cycles = m_cycles as u32;
-        self.m_cycle(m_cycles);
-        if self.mmu.ppu.vblank {
-            to.draw = true;
-        }
+        self.m_cycle(m_cycles.saturating_sub(self.spent));
+        to.draw = std::mem::take(&mut self.draw);
         if self.sc_enable {
             to.sb = Some(self.sb);
             self.sc_enable = false;
@@ -527,6 +570,12 @@ impl Cpu {
         1
     }
 
+    fn div_apu(&mut self, apu_bit: bool) {
+        if apu_bit && !self.timer.apu_bit(self.mmu.double_speed) {
+            self.mmu.apu.frame_sequencer();
+        }
+    }
+
     fn ei(&mut self) -> u16 {
         // println!("EI");
         self.ei = true;
@@ -714,7 +763,7 @@ impl Cpu {
         let addr: u16 = self.next_word();
         let (hi, lo) = split_u16(self.sp);
         self.write_byte(addr, lo);
-        self.write_byte(addr + 1, hi);
+        self.write_byte(addr.wrapping_add(1), hi);
         5
     }
 
@@ -752,12 +801,32 @@ impl Cpu {
 
     fn m_cycle(&mut self, cycles: u16) {
         let stat: bool = self.mmu.ppu.int_line;
-        self.mmu.cycle(cycles);
+        let mut paused: u16 = 0;
+        for _ in 0..cycles {
+            if self.stopped {
+                break;
+            }
+            if self.speed_switch > 0 {
+                // nothing runs during the switch, not even DIV
+                self.speed_switch -= 1;
+                paused += 1;
+                continue;
+            }
+            let apu_bit: bool = self.timer.apu_bit(self.mmu.double_speed);
+            if self.timer.tick() {
+                self.iflags.set(TimerInt);
+            }
+            self.div_apu(apu_bit);
+        }
+        let cycles: u16 = cycles - paused;
+        // the timer and OAM DMA run on the CPU clock, everything else at normal speed
+        let normal_cycles: u16 = self.normal_cycles(cycles);
+        self.mmu.cycle(normal_cycles);
         if self.mmu.ppu.dma {
             let mut cycles: u8 = cycles as u8;
             while cycles > 0 && self.dma_cycles < 160 {
                 cycles -= 1;
-                let obj: u8 = self.read_byte(self.mmu.ppu.dma_src | self.dma_cycles as u16);
+                let obj: u8 = self.read_bus(self.mmu.ppu.dma_src | self.dma_cycles as u16);
                 self.mmu.ppu.dma_transfer(obj, self.dma_cycles);
                 self.dma_cycles += 1;
             }
@@ -766,17 +835,54 @@ impl Cpu {
                 self.dma_cycles = 0;
             }
         }
+        if self.mmu.ppu.gdma {
+            self.vram_dma(self.mmu.ppu.hdma_blocks());
+        } else if self.mmu.ppu.hdma && self.mmu.ppu.hblank {
+            self.vram_dma(1);
+        }
         if self.mmu.ppu.vblank {
             self.iflags.set(VBlank);
+            self.draw = true;
+        }
+        if self.mmu.joypad.interrupt {
+            self.mmu.joypad.interrupt = false;
+            self.iflags.set(Joypad);
         }
         if !stat && self.mmu.ppu.int_line {
             self.iflags.set(Stat);
         }
     }
 
+    /// Runs one M-cycle of the current instruction ahead of the rest.
+    fn idle(&mut self) {
+        self.m_cycle(1);
+        self.spent += 1;
+    }
+
+    /// Copies `blocks` 16-byte blocks into VRAM. The CPU is paused for 8 normal-speed
+    /// M-cycles per block.
+    fn vram_dma(&mut self, blocks: u16) {
+        for _ in 0..blocks * 16 {
+            let byte: u8 = self.read_bus(self.mmu.ppu.hdma_src);
+            self.mmu.ppu.hdma_transfer(byte);
+        }
+        self.hdma_cycles += (blocks * 8) << self.mmu.double_speed as u16;
+    }
+
+    /// Converts CPU M-cycles to normal-speed ones, which take two CPU M-cycles each in
+    /// double speed.
+    fn normal_cycles(&mut self, cycles: u16) -> u16 {
+        if !self.mmu.double_speed {
+            return cycles;
+        }
+        let cycles: u16 = cycles + self.half_cycle as u16;
+        self.half_cycle = cycles & 1 == 1;
+        cycles / 2
+    }
+
     fn next_byte(&mut self) -> u8 {
         let byte = self.read_byte(self.pc);
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         byte
     }
 
@@ -788,8 +894,8 @@ impl Cpu {
 
     fn pop(&mut self) -> u16 {
         let lo: u8 = self.read_byte(self.sp);
-        let hi: u8 = self.read_byte(self.sp + 1);
-        self.sp += 2;
+        let hi: u8 = self.read_byte(self.sp.wrapping_add(1));
+        self.sp = self.sp.wrapping_add(2);
         combine_u8(hi, lo)
     }
 
@@ -808,9 +914,12 @@ impl Cpu {
 
     fn push(&mut self, b: u16) {
         let (hi, lo) = split_u16(b);
-        self.write_byte(self.sp - 1, hi);
-        self.write_byte(self.sp - 2, lo);
-        self.sp -= 2;
+        // SP is decremented in an M-cycle of its own before the writes
+        self.idle();
+        // SP wraps around the address space like any other 16-bit register
+        self.write_byte(self.sp.wrapping_sub(1), hi);
+        self.write_byte(self.sp.wrapping_sub(2), lo);
+        self.sp = self.sp.wrapping_sub(2);
     }
 
     fn push_r16(&mut self, r: R16) -> u16 {
@@ -843,13 +952,19 @@ impl Cpu {
         }
     }
 
+    /// Reads at the end of an M-cycle of the instruction.
     fn read_byte(&mut self, addr: u16) -> u8 {
+        self.idle();
+        self.read_bus(addr)
+    }
+
+    /// Reads without spending an M-cycle, for DMA and internal accesses.
+    fn read_bus(&mut self, addr: u16) -> u8 {
         match addr {
             SB => self.sb,
             SC => self.read_sc(),
             IF => self.iflags.line,
-            TIMA | TMA | TAC | DIV => 0xFF,
-            0xFF10..0xFF40 => self.garbage[addr as usize], // misc. unimplemented
+            DIV..=TAC => self.timer.read_byte(addr),
             0xFF80..0xFFFF => self.hram[addr as usize - 0xFF80],
             IE => self.ienable.line,
             _ => self.mmu.read_byte(addr),
@@ -1017,7 +1132,14 @@ impl Cpu {
     }
 
     fn stop(&mut self) -> u16 {
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
+        self.write_bus(DIV, 0);
+        // in CGB mode an armed KEY1 turns STOP into a speed switch
+        if self.mmu.switch_speed() {
+            self.speed_switch = SPEED_SWITCH_CYCLES;
+        } else {
+            self.stopped = true;
+        }
         1
     }
 
@@ -1026,13 +1148,22 @@ impl Cpu {
         (b << 4) | (b >> 4)
     }
 
+    /// Writes at the end of an M-cycle of the instruction.
     fn write_byte(&mut self, addr: u16, b: u8) {
+        self.idle();
+        self.write_bus(addr, b);
+    }
+
+    fn write_bus(&mut self, addr: u16, b: u8) {
         match addr {
             SB => self.sb = b,
             SC => self.serial_control(b),
             IF => self.iflags.line = b,
-            DIV | TMA | TIMA | TAC => (),
-            0xFF10..0xFF40 => self.garbage[addr as usize] = b,
+            DIV..=TAC => {
+                let apu_bit: bool = self.timer.apu_bit(self.mmu.double_speed);
+                self.timer.write_byte(addr, b);
+                self.div_apu(apu_bit);
+            }
             0xFF80..0xFFFF => self.hram[addr as usize - 0xFF80] = b,
             IE => self.ienable.line = b,
             _ => self.mmu.write_byte(addr, b),
@@ -1216,3 +1347,223 @@ impl std::fmt::Display for Interrupt {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::mbc0::Mbc0;
+
+    /// Boots straight into `code` at 0x0100.
+    fn cpu(code: &[u8]) -> Cpu {
+        let mut rom: Vec<u8> = vec![0; 0x8000];
+        rom[0x0100..0x0100 + code.len()].copy_from_slice(code);
+        Cpu::boot(Box::new(Mbc0::new(rom)), Model::Dmg)
+    }
+
+    #[test]
+    fn reads_see_their_own_m_cycle() {
+        // LDH A,(STAT) reads in its third M-cycle, 12 dots after it starts
+        let mut cpu: Cpu = cpu(&[0xF0, 0x41].repeat(8));
+        for _ in 0..6 {
+            cpu.tick();
+        }
+        assert_eq!(cpu.rg[A as usize] & 3, 2);
+        // the seventh starts at dot 72 but reads at 84, inside Mode 3
+        cpu.tick();
+        assert_eq!(cpu.rg[A as usize] & 3, 3);
+    }
+
+    #[test]
+    fn v_blank_is_drawn_once() {
+        let mut cpu: Cpu = cpu(&[0x18, 0xFE]);
+        let mut frames: u32 = 0;
+        // 154 lines of 114 M-cycles, JR takes 3
+        for _ in 0..154 * 114 / 3 + 1 {
+            frames += cpu.tick().draw as u32;
+        }
+        assert_eq!(frames, 1);
+    }
+
+    /// Runs `code` with SP at 0xFF05, so a push writes its high byte to DIV, and TIMA
+    /// clocked every 4 M-cycles. Returns the NOPs after it until TIMA next ticks, which
+    /// counts back to the M-cycle that reset DIV.
+    fn nops_to_tima(code: &[u8]) -> u16 {
+        let mut rom: Vec<u8> = code.to_vec();
+        rom.resize(0x40, 0x00);
+        let mut cpu: Cpu = cpu(&rom);
+        cpu.sp = 0xFF05;
+        cpu.timer.write_byte(TAC, 0x05);
+        cpu.tick();
+        let tima: u8 = cpu.timer.read_byte(TIMA);
+        let mut nops: u16 = 0;
+        while cpu.timer.read_byte(TIMA) == tima {
+            cpu.tick();
+            nops += 1;
+        }
+        nops
+    }
+
+    #[test]
+    fn writes_land_on_their_m_cycle() {
+        // LDH (DIV),A writes at the end of its last M-cycle
+        assert_eq!(nops_to_tima(&[0xE0, 0x04]), 4);
+        // PUSH BC writes B in its third M-cycle of four
+        assert_eq!(nops_to_tima(&[0xC5]), 3);
+        // CALL writes the high byte of PC in its fifth M-cycle of six
+        assert_eq!(nops_to_tima(&[0xCD, 0x10, 0x01]), 3);
+    }
+
+    #[test]
+    fn speed_switch_pauses_everything() {
+        // LD A,1; LDH (KEY1),A; STOP
+        let mut cpu: Cpu = cpu(&[0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00]);
+        cpu.mmu.set_cgb();
+        for _ in 0..3 {
+            cpu.tick();
+        }
+        assert_eq!(cpu.read_bus(0xFF4D), 0xFE);
+        let ly: u8 = cpu.mmu.ppu.ly;
+        // the switch lasts longer than a dozen lines
+        for _ in 0..SPEED_SWITCH_CYCLES - 1 {
+            cpu.tick();
+        }
+        assert_eq!((cpu.read_bus(DIV), cpu.mmu.ppu.ly), (0, ly));
+        // then double speed, two CPU M-cycles to each line's 114
+        for _ in 0..230 {
+            cpu.tick();
+        }
+        assert_ne!(cpu.mmu.ppu.ly, ly);
+    }
+
+    /// A CGB CPU about to run `code`, with VRAM DMA pointed from 0xC000, which holds
+    /// 1, 2, 3 and so on, to 0x8000.
+    fn vram_dma(code: &[u8]) -> Cpu {
+        let mut cpu: Cpu = cpu(code);
+        cpu.mmu.set_cgb();
+        for i in 0..0x80 {
+            cpu.mmu.write_byte(0xC000 + i, i as u8 + 1);
+        }
+        for (reg, b) in [(HDMA1, 0xC0), (HDMA2, 0x00), (HDMA3, 0x00), (HDMA4, 0x00)] {
+            cpu.mmu.write_byte(reg, b);
+        }
+        cpu
+    }
+
+    /// Turns the LCD off to count the bytes that reached VRAM.
+    fn vram_copied(cpu: &mut Cpu) -> u16 {
+        cpu.mmu.write_byte(LCDC, 0x00);
+        (0..0x80)
+            .take_while(|i| cpu.mmu.read_byte(0x8000 + i) == *i as u8 + 1)
+            .count() as u16
+    }
+
+    fn run_to_line(cpu: &mut Cpu, ly: u8) {
+        while cpu.mmu.ppu.ly != ly {
+            cpu.tick();
+        }
+    }
+
+    #[test]
+    fn general_purpose_dma() {
+        // LD A,1; LDH (HDMA5),A copies two blocks at once
+        let mut cpu: Cpu = vram_dma(&[0x3E, 0x01, 0xE0, 0x55]);
+        cpu.tick();
+        cpu.tick();
+        assert_eq!(cpu.mmu.read_byte(HDMA5), 0xFF);
+        // then the CPU sits out 8 M-cycles per block
+        let pc: u16 = cpu.pc;
+        let mut stalled: u32 = 0;
+        loop {
+            let m_cycles: u32 = cpu.tick().m_cycles;
+            if cpu.pc != pc {
+                break;
+            }
+            stalled += m_cycles;
+        }
+        assert_eq!(stalled, 16);
+        assert_eq!(vram_copied(&mut cpu), 0x20);
+    }
+
+    #[test]
+    fn h_blank_dma() {
+        // LD A,0x82; LDH (HDMA5),A copies three blocks, one per H-Blank
+        let mut cpu: Cpu = vram_dma(&[0x3E, 0x82, 0xE0, 0x55]);
+        cpu.tick();
+        cpu.tick();
+        assert_eq!(cpu.mmu.read_byte(HDMA5), 0x02);
+        run_to_line(&mut cpu, 1);
+        assert_eq!(cpu.mmu.read_byte(HDMA5), 0x01);
+        run_to_line(&mut cpu, 2);
+        assert_eq!(cpu.mmu.read_byte(HDMA5), 0x00);
+        run_to_line(&mut cpu, 3);
+        assert_eq!(cpu.mmu.read_byte(HDMA5), 0xFF);
+        run_to_line(&mut cpu, 4);
+        assert_eq!(vram_copied(&mut cpu), 0x30);
+    }
+
+    #[test]
+    fn h_blank_dma_cancelled() {
+        let mut cpu: Cpu = vram_dma(&[0x3E, 0x83, 0xE0, 0x55]);
+        cpu.tick();
+        cpu.tick();
+        run_to_line(&mut cpu, 1);
+        // clearing bit 7 stops it, leaving the remaining length readable
+        cpu.mmu.write_byte(HDMA5, 0x00);
+        assert_eq!(cpu.mmu.read_byte(HDMA5), 0x82);
+        run_to_line(&mut cpu, 4);
+        assert_eq!(cpu.mmu.read_byte(HDMA5), 0x82);
+        assert_eq!(vram_copied(&mut cpu), 0x10);
+    }
+
+    /// Runs a screenshot test ROM until it executes LD B,B, its signal that the screen is
+    /// finished, then lets one more frame through.
+    #[cfg(feature = "png")]
+    fn screenshot(rom: Vec<u8>) -> Vec<u8> {
+        let mut cpu: Cpu = Cpu::boot(crate::mbc::from_vec(rom).unwrap().0, Model::Dmg);
+        let mut frames: u32 = 0;
+        while cpu.mmu.read_byte(cpu.pc) != 0x40 {
+            frames += cpu.tick().draw as u32;
+            assert!(frames < 600, "no LD B,B after 10 seconds");
+        }
+        while !cpu.tick().draw {}
+        cpu.mmu.ppu.shade_buffer.to_vec()
+    }
+
+    /// Decodes a reference screenshot to DMG shades, lightest first.
+    #[cfg(feature = "png")]
+    fn reference(path: &std::path::Path) -> Vec<u8> {
+        let mut decoder = png::Decoder::new(std::fs::File::open(path).unwrap());
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let mut reader = decoder.read_info().unwrap();
+        let mut buf: Vec<u8> = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf).unwrap();
+        assert_eq!((info.width, info.height), (160, 144));
+        buf[..info.buffer_size()]
+            .chunks_exact(info.color_type.samples())
+            .map(|px| 3 - px[0] / 64)
+            .collect()
+    }
+
+    /// Compares every `<name>.gb` in `GB_TEST_ROMS` against the `<name>.png` next to it,
+    /// e.g. dmg-acid2 with its DMG reference and the mealybug-tearoom PPU tests with their
+    /// DMG-blob screenshots. Run with `cargo test --features png -- --ignored`.
+    #[cfg(feature = "png")]
+    #[test]
+    #[ignore = "needs GB_TEST_ROMS"]
+    fn screenshots() {
+        let dir: String = std::env::var("GB_TEST_ROMS").expect("GB_TEST_ROMS is not set");
+        let mut failed: Vec<String> = Vec::new();
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path: std::path::PathBuf = entry.unwrap().path();
+            let expected: std::path::PathBuf = path.with_extension("png");
+            if path.extension().is_none_or(|ext| ext != "gb") || !expected.exists() {
+                continue;
+            }
+            let shades: Vec<u8> = screenshot(std::fs::read(&path).unwrap());
+            if shades != reference(&expected) {
+                failed.push(path.display().to_string());
+            }
+        }
+        assert!(failed.is_empty(), "screenshots differ: {:?}", failed);
+    }
+}
@@ -1,27 +1,215 @@
-extern "C" {
-    fn start_clk(clk: *mut SysClk, cb: extern fn(*mut SysClk, u16));
+use crate::utils::*;
+
+pub struct Timer {
+    div: u16, // internal 16-bit divider, DIV is the upper byte
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    overflow: bool,
+    reloading: bool, // the M-cycle TIMA is loaded from TMA
 }
 
-#[repr(C)]
-pub struct SysClk {
-    pub div: u16,
-    pub tma: u8,
-    pub tima: u8,
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-#[no_mangle]
-extern "C" fn update_div(clk: *mut SysClk, div: u16) {
-    unsafe {
-        (*clk).div = div;
+impl Timer {
+    pub fn new() -> Self {
+        Self {
+            div: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            overflow: false,
+            reloading: false,
+        }
+    }
+
+    pub fn boot() -> Self {
+        Self {
+            div: 0xABCC,
+            ..Self::new()
+        }
+    }
+
+    /// Advances the timer by one M-cycle, returning true when TIMA reloads from TMA
+    /// and the timer interrupt should be requested.
+    pub fn tick(&mut self) -> bool {
+        let interrupt: bool = self.overflow;
+        self.reloading = self.overflow;
+        if self.overflow {
+            // TIMA reads 0x00 for one M-cycle after overflowing before the reload
+            self.overflow = false;
+            self.tima = self.tma;
+        }
+        let signal: bool = self.signal();
+        self.div = self.div.wrapping_add(4);
+        self.edge(signal);
+        interrupt
+    }
+
+    /// DIV bit 4, whose falling edge clocks the APU frame sequencer. DIV runs twice
+    /// as fast in double speed, so bit 5 keeps the sequencer at 512 Hz.
+    pub fn apu_bit(&self, double_speed: bool) -> bool {
+        bit(self.div, 12 + double_speed as u8)
+    }
+
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            DIV => (self.div >> 8) as u8,
+            TIMA => self.tima,
+            TMA => self.tma,
+            TAC => self.tac | 0xF8,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write_byte(&mut self, addr: u16, b: u8) {
+        match addr {
+            DIV => {
+                let signal: bool = self.signal();
+                self.div = 0;
+                self.edge(signal);
+            }
+            // writing during the reload delay cancels the reload, writing while it
+            // happens is ignored
+            TIMA if self.reloading => (),
+            TIMA => {
+                self.overflow = false;
+                self.tima = b;
+            }
+            TMA => {
+                self.tma = b;
+                if self.reloading {
+                    self.tima = b;
+                }
+            }
+            TAC => {
+                let signal: bool = self.signal();
+                self.tac = b;
+                self.edge(signal);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn edge(&mut self, signal: bool) {
+        // TIMA is clocked by the falling edge of (TAC enable & selected divider bit)
+        if signal && !self.signal() {
+            let (tima, overflow) = self.tima.overflowing_add(1);
+            self.tima = tima;
+            self.overflow = overflow;
+        }
+    }
+
+    fn signal(&self) -> bool {
+        let shift: u8 = match self.tac & 3 {
+            0 => 9, // 4096 Hz
+            1 => 3, // 262144 Hz
+            2 => 5, // 65536 Hz
+            3 => 7, // 16384 Hz
+            _ => unreachable!(),
+        };
+        bit(self.tac, 2) && bit(self.div, shift)
     }
 }
 
-impl SysClk {
-    pub fn start() -> Box<SysClk> {
-        let mut clk = Box::new(SysClk { div: 0, tma: 0, tima: 0 });
-        unsafe {
-            start_clk(&mut *clk, update_div);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A timer clocked from DIV bit 3 that overflows on its next M-cycle.
+    fn overflowing() -> Timer {
+        let mut timer: Timer = Timer::new();
+        timer.write_byte(TMA, 0x42);
+        timer.write_byte(TIMA, 0xFF);
+        timer.write_byte(TAC, 0x05);
+        for _ in 0..3 {
+            timer.tick();
+        }
+        timer
+    }
+
+    #[test]
+    fn rates() {
+        // M-cycles per increment for each TAC clock select
+        for (tac, period) in [(0x04, 256), (0x05, 4), (0x06, 16), (0x07, 64)] {
+            let mut timer: Timer = Timer::new();
+            timer.write_byte(TAC, tac);
+            for _ in 0..period - 1 {
+                timer.tick();
+            }
+            assert_eq!(timer.read_byte(TIMA), 0, "TAC {:02X}", tac);
+            timer.tick();
+            assert_eq!(timer.read_byte(TIMA), 1, "TAC {:02X}", tac);
         }
-        return clk;
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn falling_edges() {
+        // DIV bit 3 is high after two M-cycles
+        let mut timer: Timer = Timer::new();
+        timer.write_byte(TAC, 0x05);
+        timer.tick();
+        timer.tick();
+        timer.write_byte(DIV, 0xAB);
+        assert_eq!((timer.read_byte(DIV), timer.read_byte(TIMA)), (0, 1));
+        // resetting DIV with the bit low does nothing
+        timer.write_byte(DIV, 0);
+        assert_eq!(timer.read_byte(TIMA), 1);
+
+        timer.tick();
+        timer.tick();
+        timer.write_byte(TAC, 0x01);
+        assert_eq!(timer.read_byte(TIMA), 2);
+
+        // bit 3 high, bit 5 low
+        timer.write_byte(TAC, 0x05);
+        timer.write_byte(TAC, 0x06);
+        assert_eq!(timer.read_byte(TIMA), 3);
+    }
+
+    #[test]
+    fn delayed_reload() {
+        let mut timer: Timer = overflowing();
+        assert!(!timer.tick());
+        assert_eq!(timer.read_byte(TIMA), 0x00);
+        // the interrupt is requested with the reload, one M-cycle later
+        assert!(timer.tick());
+        assert_eq!(timer.read_byte(TIMA), 0x42);
+        assert!(!timer.tick());
+    }
+
+    #[test]
+    fn writes_during_delay() {
+        let mut timer: Timer = overflowing();
+        timer.tick();
+        timer.write_byte(TIMA, 0x10);
+        assert!(!timer.tick());
+        assert_eq!(timer.read_byte(TIMA), 0x10);
+
+        let mut timer: Timer = overflowing();
+        timer.tick();
+        timer.write_byte(TMA, 0x99);
+        assert!(timer.tick());
+        assert_eq!(timer.read_byte(TIMA), 0x99);
+    }
+
+    #[test]
+    fn writes_during_reload() {
+        let mut timer: Timer = overflowing();
+        timer.tick();
+        timer.tick();
+        timer.write_byte(TIMA, 0x10);
+        assert_eq!(timer.read_byte(TIMA), 0x42);
+        timer.write_byte(TMA, 0x77);
+        assert_eq!(timer.read_byte(TIMA), 0x77);
+        // afterwards both behave normally again
+        timer.tick();
+        timer.write_byte(TIMA, 0x10);
+        timer.write_byte(TMA, 0x20);
+        assert_eq!(timer.read_byte(TIMA), 0x10);
+    }
+}
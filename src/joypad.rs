@@ -0,0 +1,126 @@
+use crate::utils::*;
+use crate::GbInput;
+
+pub struct Joypad {
+    select: u8, // P1 bits 4 (d-pad) and 5 (buttons), active low
+    input: GbInput,
+    pub interrupt: bool,
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Self {
+            select: 0x30,
+            input: GbInput::default(),
+            interrupt: false,
+        }
+    }
+
+    pub fn read_byte(&self) -> u8 {
+        0xC0 | self.select | self.lines()
+    }
+
+    pub fn write_byte(&mut self, b: u8) {
+        let lines: u8 = self.lines();
+        self.select = b & 0x30;
+        self.update(lines);
+    }
+
+    pub fn set_input(&mut self, input: GbInput) {
+        let lines: u8 = self.lines();
+        self.input = input;
+        self.update(lines);
+    }
+
+    /// True while any selected input line is held low, which is what wakes the CPU from STOP.
+    pub fn active(&self) -> bool {
+        self.lines() != 0x0F
+    }
+
+    fn lines(&self) -> u8 {
+        let mut lines: u8 = 0x0F;
+        if !bit(self.select, 4) {
+            lines &= !(self.input.right as u8
+                | (self.input.left as u8) << 1
+                | (self.input.up as u8) << 2
+                | (self.input.down as u8) << 3);
+        }
+        if !bit(self.select, 5) {
+            lines &= !(self.input.a as u8
+                | (self.input.b as u8) << 1
+                | (self.input.select as u8) << 2
+                | (self.input.start as u8) << 3);
+        }
+        lines
+    }
+
+    fn update(&mut self, lines: u8) {
+        // the interrupt fires on any high-to-low transition of P10-P13
+        if lines & !self.lines() != 0 {
+            self.interrupt = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn held(right: bool, b: bool) -> GbInput {
+        GbInput {
+            right,
+            b,
+            ..GbInput::default()
+        }
+    }
+
+    #[test]
+    fn select_matrix() {
+        let mut joypad: Joypad = Joypad::new();
+        joypad.set_input(held(true, true));
+        // P14 low reads the d-pad, P15 low the buttons, both low ANDs them
+        let reads: Vec<u8> = [0x20, 0x10, 0x00, 0x30]
+            .iter()
+            .map(|select| {
+                joypad.write_byte(*select);
+                joypad.read_byte()
+            })
+            .collect();
+        assert_eq!(reads, [0xEE, 0xDD, 0xCC, 0xFF]);
+    }
+
+    #[test]
+    fn interrupt_on_falling_line() {
+        let mut joypad: Joypad = Joypad::new();
+        joypad.write_byte(0x20);
+        joypad.set_input(held(false, true));
+        assert!(!joypad.interrupt);
+        joypad.set_input(held(true, true));
+        assert!(joypad.interrupt);
+        // releasing raises the line again
+        joypad.interrupt = false;
+        joypad.set_input(held(false, true));
+        assert!(!joypad.interrupt);
+        // selecting a row with a button already held pulls its line low too
+        joypad.write_byte(0x10);
+        assert!(joypad.interrupt);
+    }
+
+    #[test]
+    fn wakes_from_stop() {
+        let mut joypad: Joypad = Joypad::new();
+        joypad.write_byte(0x20);
+        assert!(!joypad.active());
+        // only a selected row can wake the CPU
+        joypad.set_input(held(false, true));
+        assert!(!joypad.active());
+        joypad.set_input(held(true, true));
+        assert!(joypad.active());
+    }
+}
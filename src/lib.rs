@@ -1,52 +1,219 @@
 use crate::cpu::{Cpu, CpuTickOutput};
+use crate::mbc::image::ImageSource;
+use crate::mbc::ir::IrLink;
+use crate::mbc::rtc::ClockSource;
+use crate::mbc::{from_vec_with_clock, make_mbc, Cartridge, CartridgeHeader, LoadError, MbcEvent};
+use crate::model::Model;
+use crate::ppu::DMG_PALETTE;
+use crate::save::{SaveFile, AUTOSAVE_FRAMES};
+use crate::sgb::{Sgb, SGB_HEIGHT, SGB_WIDTH};
 use std::sync::mpsc::{Receiver, Sender};
+use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
 
+pub mod apu;
+#[cfg(feature = "archive")]
+pub mod archive;
 pub mod cpu;
+pub mod joypad;
 pub mod mbc;
 pub mod mmu;
+pub mod model;
+pub mod patch;
 pub mod ppu;
+pub mod save;
+pub mod sgb;
 mod utils;
 
 pub const CLOCK_SPEED: u32 = 1048576;
+pub const CYCLES_PER_FRAME: u32 = 17556;
+pub const FRAME_RATE: f64 = CLOCK_SPEED as f64 / CYCLES_PER_FRAME as f64;
 pub const NANOS_PER_CYCLE: f64 = (1_000_000_000f64) / (CLOCK_SPEED as f64);
 
-pub struct GbInput {}
+#[derive(Default, Clone, Copy)]
+pub struct GbInput {
+    pub right: bool,
+    pub left: bool,
+    pub up: bool,
+    pub down: bool,
+    pub a: bool,
+    pub b: bool,
+    pub select: bool,
+    pub start: bool,
+    /// Tilt for accelerometer cartridges, in g: +x tilts right and +y tilts down.
+    pub tilt_x: f32,
+    pub tilt_y: f32,
+}
 
 pub struct GbOutput {
-    pub frame: [u8; 160 * 144],
+    /// RGB555 pixels, 160x144 or 256x224 with a Super Game Boy border.
+    pub frame: Vec<u16>,
+    pub width: usize,
+    pub height: usize,
+    /// Interleaved stereo samples at `apu::SAMPLE_RATE` produced during this frame.
+    pub samples: Vec<f32>,
+    pub events: Vec<MbcEvent>,
+}
+
+/// Input channel, frame channel, the emulator thread and the cartridge header returned
+/// by `run_cpu`.
+pub type CpuHandle = (
+    Sender<GbInput>,
+    Receiver<GbOutput>,
+    JoinHandle<()>,
+    CartridgeHeader,
+);
+
+/// How to start the emulator.
+#[derive(Default)]
+pub struct Options {
+    /// Defaults to CGB for cartridges with CGB support and DMG otherwise.
+    pub model: Option<Model>,
+    /// A boot ROM image to run first. Without one, emulation starts from the state
+    /// the model's boot ROM would have left behind.
+    pub boot_rom: Option<String>,
+    /// The file to load from a zip archive, by default its first `.gb`/`.gbc`.
+    pub entry: Option<String>,
+    /// Where cartridge clocks take their time from. `Cycles` makes runs reproducible.
+    pub clock: ClockSource,
+    /// What the Pocket Camera sees, by default a test pattern.
+    pub image_source: Option<Box<dyn ImageSource>>,
+    /// The other end of the HuC1/HuC3 infrared port, by default nothing.
+    pub ir_link: Option<Box<dyn IrLink>>,
+}
+
+impl std::fmt::Debug for Options {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Options")
+            .field("model", &self.model)
+            .field("boot_rom", &self.boot_rom)
+            .field("entry", &self.entry)
+            .field("clock", &self.clock)
+            .field("image_source", &self.image_source.is_some())
+            .field("ir_link", &self.ir_link.is_some())
+            .finish()
+    }
 }
 
-pub fn run_cpu(fp: &str) -> (Sender<GbInput>, Receiver<Vec<u8>>) {
-    let mut cpu = Box::new(Cpu::boot(fp));
+/// Runs the emulator on its own thread. Frames are handed over through a bounded
+/// channel, so the frontend paces emulation by how quickly it receives them. Dropping
+/// the channels stops the thread, which flushes battery-backed RAM before exiting.
+pub fn run_cpu(fp: &str, options: Options) -> Result<CpuHandle, LoadError> {
+    let cart = make_mbc(fp, options.entry.as_deref(), options.clock)?;
+    start(cart, Some(SaveFile::new(fp)), options)
+}
+
+/// Like `run_cpu` for a ROM that's already in memory. Nothing is read or written to
+/// disk besides the boot ROM, so battery-backed RAM is lost when the thread stops.
+pub fn run_cpu_bytes(rom: &[u8], options: Options) -> Result<CpuHandle, LoadError> {
+    start(
+        from_vec_with_clock(rom.to_vec(), options.clock)?,
+        None,
+        options,
+    )
+}
+
+fn start(
+    (mut cart, header): Cartridge,
+    mut save: Option<SaveFile>,
+    options: Options,
+) -> Result<CpuHandle, LoadError> {
+    if let Some(source) = options.image_source {
+        cart.set_image_source(source);
+    }
+    if let Some(link) = options.ir_link {
+        cart.set_ir_link(link);
+    }
+    let cgb: bool = header.cgb();
+    let sgb: bool = header.sgb();
+    let model: Model = options
+        .model
+        .unwrap_or(if cgb { Model::Cgb } else { Model::Dmg });
+    let has_boot_rom: bool = options.boot_rom.is_some();
+    let mut cpu = Box::new(match options.boot_rom {
+        Some(path) => {
+            let boot_rom: Vec<u8> = std::fs::read(path)?;
+            let expected: usize = model.boot_rom_size();
+            if boot_rom.len() != expected {
+                return Err(LoadError::BootRom(format!(
+                    "{:?} boot ROM should be {} bytes, got {}",
+                    model,
+                    expected,
+                    boot_rom.len()
+                )));
+            }
+            Cpu::with_boot_rom(cart, boot_rom)
+        }
+        None => Cpu::boot(cart, model),
+    });
+    // the boot ROM drops DMG cartridges into compatibility mode through KEY0
+    if model == Model::Cgb {
+        cpu.mmu.set_cgb();
+        if !cgb && !has_boot_rom {
+            cpu.mmu.set_dmg_compat();
+            cpu.mmu.ppu.set_compat_palettes(DMG_PALETTE);
+        }
+    }
+    // the SGB ignores packets from games that don't declare support
+    if sgb && matches!(model, Model::Sgb | Model::Sgb2) {
+        cpu.mmu.sgb = Some(Sgb::new());
+    }
+    if let Some(save) = save.as_mut() {
+        save.load(&mut cpu.mmu);
+    }
     let (gbin_tx, gbin_rx) = std::sync::mpsc::channel();
     let (gbout_tx, gbout_rx) = std::sync::mpsc::sync_channel(1);
-    let frame_timer = timer(Duration::new(0, 1_000_000_000u32 / 60));
 
-    std::thread::spawn(move || 'cpu: loop {
-        'draw: loop {
-            match gbin_rx.try_recv() {
-                Ok(_) | Err(std::sync::mpsc::TryRecvError::Empty) => (),
-                Err(_) => break 'cpu,
-            }
-            let to: CpuTickOutput = cpu.tick();
-            // match to.sb {
-            //     Some(c) => {
-            //         print!("{:}", c as char);
-            //         stdout().flush().unwrap();
-            //     }
-            //     None => (),
-            // };
-            if to.draw {
-                match gbout_tx.send(cpu.mmu.ppu.display_buffer.to_vec()) {
-                    Ok(_) => break 'draw,
+    let handle = std::thread::spawn(move || {
+        let mut frames: u32 = 0;
+        'cpu: loop {
+            'draw: loop {
+                match gbin_rx.try_recv() {
+                    Ok(input) => {
+                        cpu.mmu.joypad.set_input(input);
+                        cpu.mmu.set_tilt(input.tilt_x, input.tilt_y);
+                    }
+                    Err(std::sync::mpsc::TryRecvError::Empty) => (),
                     Err(_) => break 'cpu,
                 }
+                let to: CpuTickOutput = cpu.tick();
+                // match to.sb {
+                //     Some(c) => {
+                //         print!("{:}", c as char);
+                //         stdout().flush().unwrap();
+                //     }
+                //     None => (),
+                // };
+                if to.draw {
+                    let (frame, width, height) = match cpu.mmu.sgb.as_mut() {
+                        Some(sgb) => (sgb.frame(&cpu.mmu.ppu.shade_buffer), SGB_WIDTH, SGB_HEIGHT),
+                        None => (cpu.mmu.ppu.display_buffer.to_vec(), 160, 144),
+                    };
+                    let gbout: GbOutput = GbOutput {
+                        frame,
+                        width,
+                        height,
+                        samples: cpu.mmu.apu.take_samples(),
+                        events: cpu.mmu.take_events(),
+                    };
+                    match gbout_tx.send(gbout) {
+                        Ok(_) => break 'draw,
+                        Err(_) => break 'cpu,
+                    }
+                }
             }
+            frames += 1;
+            if frames.is_multiple_of(AUTOSAVE_FRAMES) {
+                if let Some(save) = save.as_mut() {
+                    save.flush(&cpu.mmu);
+                }
+            }
+        }
+        if let Some(save) = save.as_mut() {
+            save.flush(&cpu.mmu);
         }
-        let _ = frame_timer.recv();
     });
-    (gbin_tx, gbout_rx)
+    Ok((gbin_tx, gbout_rx, handle, header))
 }
 
 pub fn timer(dur: Duration) -> Receiver<()> {
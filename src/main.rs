@@ -1,30 +1,169 @@
-use rust_gb::{run_cpu, timer, GbInput};
+use rust_gb::apu::SAMPLE_RATE;
+#[cfg(feature = "png")]
+use rust_gb::mbc::image::StaticImage;
+use rust_gb::mbc::MbcEvent;
+use rust_gb::{run_cpu, timer, GbInput, Options, FRAME_RATE};
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::controller::{Axis, GameController};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
 use sdl2::rect::Rect;
 use sdl2::render::WindowCanvas;
-use sdl2::EventPump;
 use std::process::exit;
 use std::time::{Duration, Instant};
 
-const PAL_BW: [[u8; 3]; 4] = [[155, 188, 15], [139, 172, 15], [48, 98, 48], [15, 56, 15]];
+// how much audio to keep queued ahead of the device before the emulator is held back
+const AUDIO_LATENCY: Duration = Duration::from_millis(50);
 
-fn draw_frame(canvas: &mut WindowCanvas, frame: Vec<u8>) {
-    for y in 0..144usize {
-        for x in 0..160usize {
-            let px: u8 = frame[y * 160 + x];
-            let pal = PAL_BW[px as usize];
-            let colour: Color = Color::RGB(pal[0], pal[1], pal[2]);
+/// Linear resampler from the core's native rate to the device rate, over interleaved stereo.
+struct Resampler {
+    step: f64,
+    pos: f64,
+    last: [f32; 2],
+}
+
+impl Resampler {
+    fn new(from: u32, to: u32) -> Self {
+        Self {
+            step: from as f64 / to as f64,
+            pos: 0.0,
+            last: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        let frames: usize = input.len() / 2;
+        if frames == 0 {
+            return Vec::new();
+        }
+        let frame = |i: isize| -> [f32; 2] {
+            if i < 0 {
+                self.last
+            } else {
+                [input[2 * i as usize], input[2 * i as usize + 1]]
+            }
+        };
+        let mut output: Vec<f32> = Vec::with_capacity((frames as f64 / self.step) as usize * 2 + 2);
+        while self.pos < frames as f64 {
+            let i: isize = self.pos as isize;
+            let t: f32 = (self.pos - i as f64) as f32;
+            let (a, b) = (frame(i - 1), frame(i));
+            output.push(a[0] + (b[0] - a[0]) * t);
+            output.push(a[1] + (b[1] - a[1]) * t);
+            self.pos += self.step;
+        }
+        self.pos -= frames as f64;
+        self.last = frame(frames as isize - 1);
+        output
+    }
+}
+
+/// Pixel size on screen, smaller for SGB borders so the window stays about the same size.
+fn scale(width: usize) -> u32 {
+    if width > 160 {
+        3
+    } else {
+        5
+    }
+}
+
+fn draw_frame(canvas: &mut WindowCanvas, frame: &[u16], width: usize, height: usize) {
+    let scale: u32 = scale(width);
+    // widen each 5-bit channel to 8 bits
+    let channel = |px: u16, shift: u8| -> u8 {
+        let c: u8 = (px >> shift) as u8 & 0x1F;
+        c << 3 | c >> 2
+    };
+    for y in 0..height {
+        for x in 0..width {
+            let px: u16 = frame[y * width + x];
+            let colour: Color = Color::RGB(channel(px, 0), channel(px, 5), channel(px, 10));
             canvas.set_draw_color(colour);
             canvas
-                .fill_rect(Rect::new(x as i32 * 5, y as i32 * 5, 5, 5))
+                .fill_rect(Rect::new(
+                    x as i32 * scale as i32,
+                    y as i32 * scale as i32,
+                    scale,
+                    scale,
+                ))
                 .unwrap();
         }
     }
 }
 
+fn map_key(input: &mut GbInput, keycode: Keycode, pressed: bool) {
+    match keycode {
+        Keycode::Right => input.right = pressed,
+        Keycode::Left => input.left = pressed,
+        Keycode::Up => input.up = pressed,
+        Keycode::Down => input.down = pressed,
+        Keycode::X => input.a = pressed,
+        Keycode::Z => input.b = pressed,
+        Keycode::Backspace | Keycode::RShift => input.select = pressed,
+        Keycode::Return => input.start = pressed,
+        _ => (),
+    }
+}
+
+/// Tilts by the mouse's distance from the centre of the window while the left button is held.
+fn map_mouse_tilt(input: &mut GbInput, x: i32, y: i32, held: bool, size: (u32, u32)) {
+    let (x, y) = if held {
+        (
+            x as f32 / (size.0 as f32 / 2.0) - 1.0,
+            y as f32 / (size.1 as f32 / 2.0) - 1.0,
+        )
+    } else {
+        (0.0, 0.0)
+    };
+    input.tilt_x = x.clamp(-1.0, 1.0);
+    input.tilt_y = y.clamp(-1.0, 1.0);
+}
+
+/// rust_gb [--model dmg|mgb|sgb|sgb2|cgb] [--boot-rom PATH] [--entry NAME] [--camera-image PNG] ROM
+fn parse_args() -> (String, Options) {
+    let usage = || -> ! {
+        eprintln!(
+            "USAGE: rust_gb [--model dmg|mgb|sgb|sgb2|cgb] [--boot-rom PATH] [--entry NAME] \
+             [--camera-image PNG] ROM"
+        );
+        exit(2);
+    };
+    let mut options: Options = Options::default();
+    let mut rom: Option<String> = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--model" => match args.next().map(|m| m.parse()) {
+                Some(Ok(model)) => options.model = Some(model),
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    usage();
+                }
+                None => usage(),
+            },
+            "--boot-rom" => options.boot_rom = Some(args.next().unwrap_or_else(|| usage())),
+            "--entry" => options.entry = Some(args.next().unwrap_or_else(|| usage())),
+            #[cfg(feature = "png")]
+            "--camera-image" => {
+                let path: String = args.next().unwrap_or_else(|| usage());
+                match StaticImage::from_png(&path) {
+                    Ok(image) => options.image_source = Some(Box::new(image)),
+                    Err(e) => {
+                        eprintln!("FAILED TO LOAD {}: {}", path, e);
+                        exit(1);
+                    }
+                }
+            }
+            _ if rom.is_none() => rom = Some(arg),
+            _ => usage(),
+        }
+    }
+    (rom.unwrap_or_else(|| usage()), options)
+}
+
 fn main() {
+    let (rom, options) = parse_args();
     let sdl = sdl2::init().unwrap();
     let video_subsys = sdl.video().unwrap();
     let window = video_subsys
@@ -37,9 +176,41 @@ fn main() {
     canvas.clear();
     canvas.present();
     let mut event_pump = sdl.event_pump().unwrap();
-    let args: Vec<String> = std::env::args().collect();
 
-    let (gbin_tx, gbout_rx) = run_cpu(&args[1]);
+    let audio: Option<AudioQueue<f32>> = sdl.audio().ok().and_then(|audio_subsys| {
+        let spec: AudioSpecDesired = AudioSpecDesired {
+            freq: Some(48000),
+            channels: Some(2),
+            samples: Some(1024),
+        };
+        audio_subsys.open_queue(None, &spec).ok()
+    });
+    let mut resampler: Option<Resampler> = audio.as_ref().map(|queue| {
+        queue.resume();
+        Resampler::new(SAMPLE_RATE, queue.spec().freq as u32)
+    });
+    let controller_subsys = sdl.game_controller().ok();
+    let mut controller: Option<GameController> = controller_subsys.as_ref().and_then(|subsys| {
+        (0..subsys.num_joysticks().unwrap_or(0))
+            .find(|&i| subsys.is_game_controller(i))
+            .and_then(|i| subsys.open(i).ok())
+    });
+    // without an audio device, fall back to pacing frames off the clock
+    let frame_timer = audio
+        .is_none()
+        .then(|| timer(Duration::from_secs_f64(1.0 / FRAME_RATE)));
+
+    let (gbin_tx, gbout_rx, cpu_thread, header) = match run_cpu(&rom, options) {
+        Ok(handles) => handles,
+        Err(e) => {
+            eprintln!("FAILED TO LOAD {}: {}", rom, e);
+            exit(1);
+        }
+    };
+    if !header.global_checksum_ok {
+        eprintln!("WARNING: {} FAILS ITS GLOBAL CHECKSUM", rom);
+    }
+    let mut input: GbInput = GbInput::default();
     'game: loop {
         let timer = Instant::now();
         for event in event_pump.poll_iter() {
@@ -48,24 +219,86 @@ fn main() {
                     keycode: Some(Keycode::Escape),
                     ..
                 } => break 'game,
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    ..
+                } => map_key(&mut input, keycode, true),
+                Event::KeyUp {
+                    keycode: Some(keycode),
+                    ..
+                } => map_key(&mut input, keycode, false),
+                Event::MouseMotion {
+                    x, y, mousestate, ..
+                } => map_mouse_tilt(&mut input, x, y, mousestate.left(), canvas.window().size()),
+                Event::MouseButtonUp { x, y, .. } => {
+                    map_mouse_tilt(&mut input, x, y, false, canvas.window().size())
+                }
+                Event::ControllerAxisMotion {
+                    axis: Axis::LeftX,
+                    value,
+                    ..
+                } => input.tilt_x = value as f32 / i16::MAX as f32,
+                Event::ControllerAxisMotion {
+                    axis: Axis::LeftY,
+                    value,
+                    ..
+                } => input.tilt_y = value as f32 / i16::MAX as f32,
+                Event::Quit { .. } => break 'game,
                 _ => (),
             }
         }
-        gbin_tx.send(GbInput {}).unwrap();
+        if gbin_tx.send(input).is_err() {
+            break 'game;
+        }
         match gbout_rx.recv() {
             Ok(gbout) => {
+                let scale: u32 = scale(gbout.width);
+                let size: (u32, u32) = (gbout.width as u32 * scale, gbout.height as u32 * scale);
+                if canvas.window().size() != size {
+                    let _ = canvas.window_mut().set_size(size.0, size.1);
+                }
                 canvas.clear();
-                draw_frame(&mut canvas, gbout);
+                draw_frame(&mut canvas, &gbout.frame, gbout.width, gbout.height);
                 canvas.present();
+                for event in gbout.events.iter() {
+                    if let (MbcEvent::Rumble(on), Some(controller)) = (event, controller.as_mut()) {
+                        let strength: u16 = if *on { 0xFFFF } else { 0 };
+                        let _ = controller.set_rumble(strength, strength, 1000);
+                    }
+                }
+                if let (Some(queue), Some(resampler)) = (audio.as_ref(), resampler.as_mut()) {
+                    let _ = queue.queue_audio(&resampler.process(&gbout.samples));
+                    let spec = queue.spec();
+                    let latency: u32 = (AUDIO_LATENCY.as_secs_f64()
+                        * spec.freq as f64
+                        * spec.channels as f64
+                        * size_of::<f32>() as f64) as u32;
+                    while queue.size() > latency {
+                        std::thread::sleep(Duration::from_millis(1));
+                    }
+                }
             }
             Err(_) => break 'game,
         }
+        if let Some(frame_timer) = frame_timer.as_ref() {
+            let _ = frame_timer.recv();
+        }
         let ft: u64 = timer.elapsed().as_nanos() as u64;
         let fps = 1_000_000_000f64 / ft as f64;
         let frame_time: f64 = (ft as f64) / 1_000_000f64;
         canvas
             .window_mut()
-            .set_title(format!("Rustyboy {:.2} fps | {:.2} ms", fps, frame_time).as_str())
+            .set_title(
+                format!(
+                    "Rustyboy | {} | {:.2} fps | {:.2} ms",
+                    header.title, fps, frame_time
+                )
+                .as_str(),
+            )
             .unwrap();
     }
+    // hang up on the emulator thread and let it flush the save file
+    drop(gbin_tx);
+    drop(gbout_rx);
+    let _ = cpu_thread.join();
 }
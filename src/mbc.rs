@@ -1,21 +1,165 @@
+use crate::mbc::camera::PocketCamera;
+use crate::mbc::huc1::HuC1;
+use crate::mbc::huc3::HuC3;
+use crate::mbc::image::ImageSource;
+use crate::mbc::ir::IrLink;
 use crate::mbc::mbc0::Mbc0;
 use crate::mbc::mbc1::Mbc1;
+use crate::mbc::mbc2::Mbc2;
+use crate::mbc::mbc3::Mbc3;
+use crate::mbc::mbc5::Mbc5;
+use crate::mbc::mbc6::Mbc6;
+use crate::mbc::mbc7::Mbc7;
+use crate::mbc::mmm01::Mmm01;
+use crate::mbc::rtc::ClockSource;
+use crate::mbc::tama5::Tama5;
+use crate::patch;
 use std::fs::File;
 use std::io::{BufReader, Read};
 
+pub use crate::mbc::header::CartridgeHeader;
+
+pub mod camera;
+pub mod header;
+pub mod huc1;
+pub mod huc3;
+pub mod image;
+pub mod ir;
 pub mod mbc0;
 pub mod mbc1;
+pub mod mbc2;
+pub mod mbc3;
+pub mod mbc5;
+pub mod mbc6;
+pub mod mbc7;
+pub mod mmm01;
+pub mod rtc;
+pub mod tama5;
+
+/// A mapper and the header it was picked from.
+pub type Cartridge = (Box<dyn Mbc + 'static>, CartridgeHeader);
+
+/// Loads a ROM file through `read_rom`, taking the zip entry named `entry`, and applies
+/// a patch found next to it.
+pub fn make_mbc(fp: &str, entry: Option<&str>, clock: ClockSource) -> Result<Cartridge, LoadError> {
+    let mut rom: Vec<u8> = read_rom(fp, entry)?;
+    if let Some(patch) = patch::sibling(fp) {
+        rom = patch::apply(&rom, &std::fs::read(patch)?)?;
+    }
+    from_vec_with_clock(rom, clock)
+}
 
-pub fn make_mbc(fp: &str) -> Box<dyn Mbc + 'static> {
+/// Reads a ROM file. With the `archive` feature, zip and gzip archives are unpacked,
+/// taking the zip entry named `entry` or else the first `.gb`/`.gbc` inside. Files named
+/// like plain ROMs are never unpacked, whatever their first bytes are.
+pub fn read_rom(fp: &str, entry: Option<&str>) -> Result<Vec<u8>, LoadError> {
     let mut buf = Vec::new();
-    println!("{:}", fp);
-    BufReader::new(File::open(fp).unwrap())
-        .read_to_end(&mut buf)
-        .unwrap();
-    match buf[0x0147] {
+    BufReader::new(File::open(fp)?).read_to_end(&mut buf)?;
+    #[cfg(feature = "archive")]
+    let buf: Vec<u8> = match std::path::Path::new(fp)
+        .extension()
+        .and_then(|ext| ext.to_str())
+    {
+        Some(ext)
+            if ["gb", "gbc", "sgb"]
+                .iter()
+                .any(|e| ext.eq_ignore_ascii_case(e)) =>
+        {
+            buf
+        }
+        _ => crate::archive::unpack(buf, entry)?,
+    };
+    #[cfg(not(feature = "archive"))]
+    let _ = entry;
+    Ok(buf)
+}
+
+pub fn from_reader<R: Read>(mut reader: R) -> Result<Cartridge, LoadError> {
+    let mut buf = Vec::new();
+    reader.read_to_end(&mut buf)?;
+    from_vec(buf)
+}
+
+pub fn from_slice(rom: &[u8]) -> Result<Cartridge, LoadError> {
+    from_vec(rom.to_vec())
+}
+
+/// Picks a mapper for raw ROM bytes. Archives must already be unpacked by `read_rom`.
+pub fn from_vec(buf: Vec<u8>) -> Result<Cartridge, LoadError> {
+    from_vec_with_clock(buf, ClockSource::Wall)
+}
+
+/// Like `from_vec`, with cartridge clocks counting from `clock`.
+pub fn from_vec_with_clock(buf: Vec<u8>, clock: ClockSource) -> Result<Cartridge, LoadError> {
+    // the menu's header is at the end, bank 0 holds whichever game comes first
+    if mmm01::detect(&buf) {
+        let header: CartridgeHeader = CartridgeHeader::parse_at(&buf, buf.len() - 0x8000)?;
+        return Ok((Box::new(Mmm01::new(buf)), header));
+    }
+    let header: CartridgeHeader = CartridgeHeader::parse(&buf)?;
+    let cart: Box<dyn Mbc + 'static> = match header.cartridge_type {
         0 => Box::new(Mbc0::new(buf)),
         1 | 2 | 3 => Box::new(Mbc1::new(buf)),
-        _ => todo!("UNSUPPORTED MBC {:#04x}", buf[0x0147]),
+        5 | 6 => Box::new(Mbc2::new(buf)),
+        0x0F..=0x13 => Box::new(Mbc3::new(buf, clock)),
+        0x19..=0x1E => Box::new(Mbc5::new(buf)),
+        0x20 => Box::new(Mbc6::new(buf)),
+        0x22 => Box::new(Mbc7::new(buf)),
+        0xFC => Box::new(PocketCamera::new(buf)),
+        0xFD => Box::new(Tama5::new(buf, clock)),
+        0xFE => Box::new(HuC3::new(buf, clock)),
+        0xFF => Box::new(HuC1::new(buf)),
+        t => return Err(LoadError::UnsupportedMapper(t)),
+    };
+    Ok((cart, header))
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(std::io::Error),
+    TruncatedRom {
+        expected: usize,
+        actual: usize,
+    },
+    UnsupportedMapper(u8),
+    BadHeader(String),
+    Patch(String),
+    BootRom(String),
+    #[cfg(feature = "archive")]
+    Archive(String),
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "I/O error: {}", e),
+            LoadError::TruncatedRom { expected, actual } => write!(
+                f,
+                "ROM truncated: expected at least {} bytes, got {}",
+                expected, actual
+            ),
+            LoadError::UnsupportedMapper(t) => write!(f, "unsupported cartridge type {:#04x}", t),
+            LoadError::BadHeader(msg) => write!(f, "bad cartridge header: {}", msg),
+            LoadError::Patch(msg) => write!(f, "bad patch: {}", msg),
+            LoadError::BootRom(msg) => write!(f, "bad boot ROM: {}", msg),
+            #[cfg(feature = "archive")]
+            LoadError::Archive(msg) => write!(f, "bad archive: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for LoadError {
+    fn from(e: std::io::Error) -> Self {
+        LoadError::Io(e)
     }
 }
 
@@ -24,6 +168,41 @@ pub trait Mbc: Send {
     fn read_byte(&self, addr: u16) -> u8;
     fn read_word(&self, addr: u16) -> u16;
     fn write_byte(&mut self, addr: u16, b: u8);
+
+    /// Advances anything on the cartridge that runs off the system clock.
+    fn cycle(&mut self, _cycles: u16) {}
+
+    /// Battery-backed state to persist, laid out like a raw `.sav` file.
+    fn save_data(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn load_save(&mut self, _data: &[u8]) {}
+
+    /// Accelerometer input in g along each axis, for cartridges that have one.
+    fn set_tilt(&mut self, _x: f32, _y: f32) {}
+
+    /// Points the camera sensor at `source`, for cartridges that have one.
+    fn set_image_source(&mut self, _source: Box<dyn ImageSource>) {}
+
+    /// Connects the infrared port to `link`, for cartridges that have one.
+    fn set_ir_link(&mut self, _link: Box<dyn IrLink>) {}
+
+    /// Drains cartridge hardware events raised since the last call.
+    fn take_events(&mut self) -> Vec<MbcEvent> {
+        Vec::new()
+    }
+}
+
+/// Cartridge hardware beyond memory that the frontend can reflect.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum MbcEvent {
+    /// The rumble motor was switched on or off.
+    Rumble(bool),
+    /// The infrared LED was switched on or off.
+    Infrared(bool),
+    /// The HuC3 tone generator was triggered.
+    Tone,
 }
 
 fn header_ram(v: u8) -> usize {
@@ -37,5 +216,24 @@ fn header_ram(v: u8) -> usize {
 }
 
 fn header_rom(v: u8) -> usize {
-    2 << v
+    match v {
+        0x52 => 72,
+        0x53 => 80,
+        0x54 => 96,
+        _ => 2 << v,
+    }
+}
+
+/// A ROM for mapper tests. Each 16 KiB bank starts with its number, little endian, and
+/// the header asks for 32 KiB of RAM.
+#[cfg(test)]
+pub(crate) fn banked_rom(kind: u8, rom_code: u8, banks: usize) -> Vec<u8> {
+    let mut rom: Vec<u8> = vec![0; banks * 0x4000];
+    for bank in 0..banks {
+        rom[bank * 0x4000..bank * 0x4000 + 2].copy_from_slice(&(bank as u16).to_le_bytes());
+    }
+    rom[0x0147] = kind;
+    rom[0x0148] = rom_code;
+    rom[0x0149] = 0x03;
+    rom
 }
@@ -0,0 +1,276 @@
+use crate::cpu::combine_u8;
+use crate::mbc::image::{ImageSource, SensorImage, TestPattern, SENSOR_HEIGHT, SENSOR_WIDTH};
+use crate::mbc::{header_rom, Mbc};
+use crate::utils::*;
+
+const REGISTERS: usize = 0x36;
+// where the finished picture lands in RAM bank 0
+const IMAGE_BASE: usize = 0x0100;
+// M-cycles a capture keeps the sensor busy before the exposure time is added
+const CAPTURE_CYCLES: u32 = 32446;
+const EDGE_RATIOS: [f32; 8] = [0.5, 0.75, 1.0, 1.25, 2.0, 3.0, 4.0, 5.0];
+
+pub struct PocketCamera {
+    rom: Vec<u8>,
+    eram: Vec<u8>,
+    rom_bank: usize, // 6 BITS
+    ram_bank: usize, // 0x00-0x0F RAM, 0x10 camera registers
+    ram_enable: bool,
+    rom_banks: usize,
+    regs: [u8; REGISTERS],
+    busy: u32, // M-cycles left in the current capture
+    source: Box<dyn ImageSource>,
+}
+
+impl Mbc for PocketCamera {
+    fn boot(&self) {}
+
+    fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..0x4000 => self.rom[addr as usize],
+            0x4000..0x8000 => self.rom[(addr as usize & 0x3FFF) + self.rom_bank * 0x4000],
+            // only the capture bit of A000 reads back, the rest are write-only
+            0xA000..0xC000 if self.registers() => match addr & 0x7F {
+                0x00 => self.regs[0] & 0x06 | (self.busy > 0) as u8,
+                _ => 0x00,
+            },
+            0xA000..0xC000 => self.eram_addr(addr).map_or(0xFF, |a| self.eram[a]),
+            _ => 0xFF,
+        }
+    }
+
+    fn read_word(&self, addr: u16) -> u16 {
+        combine_u8(self.read_byte(addr + 1), self.read_byte(addr))
+    }
+
+    fn write_byte(&mut self, addr: u16, b: u8) {
+        match addr {
+            0x0000..0x2000 => self.ram_enable = b & 0x0F == 0x0A,
+            0x2000..0x4000 => self.rom_bank = (b as usize & 0x3F) & (self.rom_banks - 1),
+            0x4000..0x6000 => self.ram_bank = b as usize & 0x1F,
+            0x6000..0x8000 => (),
+            0xA000..0xC000 if self.registers() => {
+                let reg: usize = addr as usize & 0x7F;
+                if reg == 0 && bit(b, 0) && self.busy == 0 {
+                    self.busy = CAPTURE_CYCLES + self.exposure() * 16;
+                }
+                if reg < REGISTERS {
+                    self.regs[reg] = b;
+                }
+            }
+            0xA000..0xC000 if self.ram_enable => {
+                if let Some(addr) = self.eram_addr(addr) {
+                    self.eram[addr] = b;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn cycle(&mut self, cycles: u16) {
+        if self.busy == 0 {
+            return;
+        }
+        self.busy = self.busy.saturating_sub(cycles as u32);
+        if self.busy == 0 {
+            self.capture();
+        }
+    }
+
+    fn save_data(&self) -> Option<Vec<u8>> {
+        Some(self.eram.clone())
+    }
+
+    fn load_save(&mut self, data: &[u8]) {
+        let len: usize = data.len().min(self.eram.len());
+        self.eram[..len].copy_from_slice(&data[..len]);
+    }
+
+    fn set_image_source(&mut self, source: Box<dyn ImageSource>) {
+        self.source = source;
+    }
+}
+
+impl PocketCamera {
+    /// POCKET CAMERA (0xFC), with 128 KiB of battery-backed RAM.
+    pub fn new(data: Vec<u8>) -> Self {
+        let rom_banks: usize = header_rom(data[0x0148]);
+        Self {
+            rom: data,
+            eram: vec![0; 0x2000 * 16],
+            rom_bank: 1,
+            ram_bank: 0,
+            ram_enable: false,
+            rom_banks,
+            regs: [0; REGISTERS],
+            busy: 0,
+            source: Box::new(TestPattern::default()),
+        }
+    }
+
+    pub fn with_image_source(mut self, source: Box<dyn ImageSource>) -> Self {
+        self.set_image_source(source);
+        self
+    }
+
+    fn registers(&self) -> bool {
+        bit(self.ram_bank as u8, 4)
+    }
+
+    fn exposure(&self) -> u32 {
+        combine_u8(self.regs[2], self.regs[3]) as u32
+    }
+
+    fn eram_addr(&self, addr: u16) -> Option<usize> {
+        let addr: usize = (addr as usize & 0x1FFF) + (self.ram_bank & 0x0F) * 0x2000;
+        (addr < self.eram.len()).then_some(addr)
+    }
+
+    /// Runs the sensor pipeline over a fresh image and stores the 2bpp tiles in RAM.
+    fn capture(&mut self) {
+        self.regs[0] &= !1;
+        let mut image: SensorImage = [0; SENSOR_WIDTH * SENSOR_HEIGHT];
+        self.source.capture(&mut image);
+        let levels: Vec<f32> = self.expose(&image);
+        let edge: bool = self.regs[1] & 0xE0 == 0xE0;
+        let ratio: f32 = EDGE_RATIOS[(self.regs[4] >> 4 & 0x07) as usize];
+        let level = |x: isize, y: isize| -> f32 {
+            let x: usize = x.clamp(0, SENSOR_WIDTH as isize - 1) as usize;
+            let y: usize = y.clamp(0, SENSOR_HEIGHT as isize - 1) as usize;
+            levels[y * SENSOR_WIDTH + x]
+        };
+        for y in 0..SENSOR_HEIGHT {
+            for x in 0..SENSOR_WIDTH {
+                let (sx, sy) = (x as isize, y as isize);
+                let mut v: f32 = level(sx, sy);
+                if edge {
+                    // 2D enhancement: add back the difference from the four neighbours
+                    let around: f32 = level(sx - 1, sy)
+                        + level(sx + 1, sy)
+                        + level(sx, sy - 1)
+                        + level(sx, sy + 1);
+                    v += (4.0 * v - around) * ratio;
+                }
+                self.store(x, y, self.dither(x, y, v));
+            }
+        }
+    }
+
+    /// Applies inversion, exposure time and gain to each pixel, on a 0-255 scale.
+    fn expose(&self, image: &SensorImage) -> Vec<f32> {
+        let invert: bool = bit(self.regs[4], 7);
+        // exposure is in steps of 16 us, 0x1000 leaves the source image as is
+        let exposure: f32 = self.exposure() as f32 / 0x1000 as f32;
+        // roughly 0.5 dB per gain step
+        let gain: f32 = 10f32.powf((self.regs[1] & 0x1F) as f32 * 0.5 / 20.0);
+        image
+            .iter()
+            .map(|&px| {
+                let px: u8 = if invert { 255 - px } else { px };
+                px as f32 * exposure * gain
+            })
+            .collect()
+    }
+
+    /// Compares against the three thresholds for this spot in the 4x4 matrix at A006-A035.
+    fn dither(&self, x: usize, y: usize, v: f32) -> u8 {
+        let base: usize = 0x06 + ((y & 3) * 4 + (x & 3)) * 3;
+        let thresholds: &[u8] = &self.regs[base..base + 3];
+        match thresholds.iter().position(|&t| v < t as f32) {
+            Some(i) => 3 - i as u8,
+            None => 0,
+        }
+    }
+
+    fn store(&mut self, x: usize, y: usize, colour: u8) {
+        let tile: usize = (y / 8) * (SENSOR_WIDTH / 8) + x / 8;
+        let addr: usize = IMAGE_BASE + tile * 16 + (y % 8) * 2;
+        let mask: u8 = 0x80 >> (x % 8);
+        for (plane, addr) in [addr, addr + 1].into_iter().enumerate() {
+            if bit(colour, plane as u8) {
+                self.eram[addr] |= mask;
+            } else {
+                self.eram[addr] &= !mask;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::banked_rom;
+
+    fn camera() -> PocketCamera {
+        // a black sensor image
+        PocketCamera::new(banked_rom(0xFC, 0x05, 64))
+            .with_image_source(Box::new(|image: &mut SensorImage| image.fill(0)))
+    }
+
+    #[test]
+    fn ram_banks() {
+        let mut camera: PocketCamera = camera();
+        camera.write_byte(0x2000, 0x3F);
+        assert_eq!(camera.read_byte(0x4000), 0x3F);
+        camera.write_byte(0x4000, 0x03);
+        camera.write_byte(0xA000, 0x42);
+        assert_eq!(camera.read_byte(0xA000), 0x00);
+        camera.write_byte(0x0000, 0x0A);
+        camera.write_byte(0xA000, 0x42);
+        camera.write_byte(0x4000, 0x00);
+        assert_eq!(camera.read_byte(0xA000), 0x00);
+        camera.write_byte(0x4000, 0x03);
+        assert_eq!(camera.read_byte(0xA000), 0x42);
+    }
+
+    #[test]
+    fn register_bank() {
+        let mut camera: PocketCamera = camera();
+        camera.write_byte(0x4000, 0x10);
+        // registers are writable without the RAM enable and leave RAM alone
+        camera.write_byte(0xA001, 0x1F);
+        camera.write_byte(0xA006, 0x80);
+        assert_eq!(camera.read_byte(0xA001), 0x00);
+        assert_eq!(camera.read_byte(0xA006), 0x00);
+        assert_eq!(camera.save_data().unwrap()[..8], [0; 8]);
+        assert_eq!(camera.regs[1], 0x1F);
+    }
+
+    /// Sets a short exposure with every threshold at mid grey and starts a capture.
+    fn start_capture(camera: &mut dyn Mbc) {
+        camera.write_byte(0x4000, 0x10);
+        camera.write_byte(0xA002, 0x10);
+        camera.write_byte(0xA003, 0x00);
+        for reg in 0x06..0x36 {
+            camera.write_byte(0xA000 + reg, 0x80);
+        }
+        camera.write_byte(0xA000, 0x03);
+    }
+
+    #[test]
+    fn capture() {
+        let mut camera: PocketCamera = camera();
+        start_capture(&mut camera);
+        assert_eq!(camera.read_byte(0xA000), 0x03);
+        camera.cycle(0xFFFF);
+        assert_eq!(camera.read_byte(0xA000), 0x03);
+        camera.cycle(0xFFFF);
+        assert_eq!(camera.read_byte(0xA000), 0x02);
+        // black pixels fall below every threshold, colour 3 in both bit planes
+        let ram: Vec<u8> = camera.save_data().unwrap();
+        assert_eq!(ram[IMAGE_BASE..IMAGE_BASE + 16], [0xFF; 16]);
+        assert_eq!(ram[IMAGE_BASE - 1], 0x00);
+    }
+
+    #[test]
+    fn image_source_through_mbc() {
+        let mut cart: Box<dyn Mbc> = Box::new(camera());
+        cart.set_image_source(Box::new(|image: &mut SensorImage| image.fill(0xFF)));
+        start_capture(cart.as_mut());
+        cart.cycle(0xFFFF);
+        cart.cycle(0xFFFF);
+        // white pixels clear every threshold, colour 0
+        let ram: Vec<u8> = cart.save_data().unwrap();
+        assert_eq!(ram[IMAGE_BASE..IMAGE_BASE + 16], [0x00; 16]);
+    }
+}
@@ -0,0 +1,239 @@
+use crate::mbc::{header_ram, header_rom, LoadError};
+
+/// The logo bitmap at 0x0104-0x0133 that the boot ROM checks before starting the cartridge.
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+/// The cartridge header at 0x0100-0x014F.
+#[derive(Clone, Debug)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub manufacturer: Option<String>,
+    pub cgb_flag: u8,
+    pub new_licensee: Option<String>,
+    pub sgb_flag: u8,
+    pub cartridge_type: u8,
+    pub rom_size: u8,
+    pub ram_size: u8,
+    pub destination: u8,
+    pub old_licensee: u8,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+    /// Hardware never checks the global checksum and plenty of dumps and hacks get it
+    /// wrong, so a mismatch is only reported here.
+    pub global_checksum_ok: bool,
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &[u8]) -> Result<Self, LoadError> {
+        Self::parse_at(rom, 0)
+    }
+
+    /// Parses a header stored at `base` instead of the start of the ROM, as MMM01 menus
+    /// are. The size and checksums are still checked against the whole ROM.
+    pub fn parse_at(full: &[u8], base: usize) -> Result<Self, LoadError> {
+        if full.len() < base + 0x150 {
+            return Err(LoadError::TruncatedRom {
+                expected: base + 0x150,
+                actual: full.len(),
+            });
+        }
+        let rom: &[u8] = &full[base..];
+        let cgb_flag: u8 = rom[0x0143];
+        // newer carts shorten the title to 11 bytes to fit a 4 character manufacturer code
+        let code: &[u8] = &rom[0x013F..0x0143];
+        let manufacturer: Option<String> = (cgb_flag & 0x80 == 0x80
+            && code
+                .iter()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit()))
+        .then(|| String::from_utf8_lossy(code).into_owned());
+        let title_end: usize = match (manufacturer.is_some(), cgb_flag & 0x80 == 0x80) {
+            (true, _) => 0x013F,
+            (false, true) => 0x0143,
+            (false, false) => 0x0144,
+        };
+        let old_licensee: u8 = rom[0x014B];
+        let header: CartridgeHeader = Self {
+            title: ascii(&rom[0x0134..title_end]),
+            manufacturer,
+            cgb_flag,
+            new_licensee: (old_licensee == 0x33).then(|| ascii(&rom[0x0144..0x0146])),
+            sgb_flag: rom[0x0146],
+            cartridge_type: rom[0x0147],
+            rom_size: rom[0x0148],
+            ram_size: rom[0x0149],
+            destination: rom[0x014A],
+            old_licensee,
+            version: rom[0x014C],
+            header_checksum: rom[0x014D],
+            global_checksum: (rom[0x014E] as u16) << 8 | rom[0x014F] as u16,
+            global_checksum_ok: false,
+        };
+        header.validate(full, rom)?;
+        Ok(Self {
+            global_checksum_ok: checksum_except(full, base) == header.global_checksum,
+            ..header
+        })
+    }
+
+    /// True if the cartridge supports CGB features (0x80) or requires them (0xC0).
+    pub fn cgb(&self) -> bool {
+        self.cgb_flag & 0x80 == 0x80
+    }
+
+    /// SGB functions are only enabled with the SGB flag and the new licensee code.
+    pub fn sgb(&self) -> bool {
+        self.sgb_flag == 0x03 && self.old_licensee == 0x33
+    }
+
+    pub fn rom_banks(&self) -> usize {
+        header_rom(self.rom_size)
+    }
+
+    pub fn ram_banks(&self) -> usize {
+        header_ram(self.ram_size)
+    }
+
+    fn validate(&self, full: &[u8], rom: &[u8]) -> Result<(), LoadError> {
+        if !matches!(self.rom_size, 0x00..=0x08 | 0x52..=0x54) {
+            return Err(LoadError::BadHeader(format!(
+                "invalid ROM size code {:#04x}",
+                self.rom_size
+            )));
+        }
+        let expected: usize = self.rom_banks() * 0x4000;
+        if full.len() < expected {
+            return Err(LoadError::TruncatedRom {
+                expected,
+                actual: full.len(),
+            });
+        }
+        let computed: u8 = header_checksum(rom);
+        if computed != self.header_checksum {
+            return Err(LoadError::BadHeader(format!(
+                "header checksum is {:#04x}, computed {:#04x}",
+                self.header_checksum, computed
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// True if the header at `base` (the start of a bank) carries the Nintendo logo.
+pub fn has_logo(rom: &[u8], base: usize) -> bool {
+    rom.get(base + 0x0104..base + 0x0134) == Some(&NINTENDO_LOGO[..])
+}
+
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    rom[0x0134..=0x014C]
+        .iter()
+        .fold(0u8, |x, b| x.wrapping_sub(*b).wrapping_sub(1))
+}
+
+/// Sum of every byte in the ROM except the global checksum itself.
+pub fn global_checksum(rom: &[u8]) -> u16 {
+    checksum_except(rom, 0)
+}
+
+fn checksum_except(rom: &[u8], base: usize) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|(i, _)| *i != base + 0x014E && *i != base + 0x014F)
+        .fold(0u16, |sum, (_, b)| sum.wrapping_add(*b as u16))
+}
+
+fn ascii(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take_while(|b| **b != 0)
+        .map(|b| {
+            if b.is_ascii_graphic() || *b == b' ' {
+                *b as char
+            } else {
+                '?'
+            }
+        })
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(rom_size: u8, ram_size: u8) -> Vec<u8> {
+        let mut rom: Vec<u8> = vec![0; header_rom(rom_size) * 0x4000];
+        rom[0x0104..0x0134].copy_from_slice(&NINTENDO_LOGO);
+        rom[0x0134..0x0138].copy_from_slice(b"TEST");
+        rom[0x0148] = rom_size;
+        rom[0x0149] = ram_size;
+        rom[0x014D] = header_checksum(&rom);
+        let sum: u16 = global_checksum(&rom);
+        rom[0x014E..0x0150].copy_from_slice(&sum.to_be_bytes());
+        rom
+    }
+
+    #[test]
+    fn parse() {
+        let rom: Vec<u8> = rom(0x01, 0x03);
+        assert!(has_logo(&rom, 0));
+        let header: CartridgeHeader = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title, "TEST");
+        assert_eq!((header.rom_banks(), header.ram_banks()), (4, 4));
+        assert!(header.global_checksum_ok);
+    }
+
+    #[test]
+    fn truncated() {
+        let rom: Vec<u8> = rom(0x02, 0x00);
+        assert!(matches!(
+            CartridgeHeader::parse(&rom[..0x100]),
+            Err(LoadError::TruncatedRom {
+                expected: 0x150,
+                actual: 0x100
+            })
+        ));
+        assert!(matches!(
+            CartridgeHeader::parse(&rom[..0x8000]),
+            Err(LoadError::TruncatedRom {
+                expected: 0x20000,
+                actual: 0x8000
+            })
+        ));
+    }
+
+    #[test]
+    fn checksums() {
+        let mut rom: Vec<u8> = rom(0x00, 0x00);
+        rom[0x0200] = 0xAA;
+        assert!(!CartridgeHeader::parse(&rom).unwrap().global_checksum_ok);
+        rom[0x014D] ^= 0xFF;
+        assert!(matches!(
+            CartridgeHeader::parse(&rom),
+            Err(LoadError::BadHeader(_))
+        ));
+    }
+
+    #[test]
+    fn size_tables() {
+        let banks: Vec<usize> = (0x00..=0x08).map(header_rom).collect();
+        assert_eq!(banks, [2, 4, 8, 16, 32, 64, 128, 256, 512]);
+        assert_eq!([0x52, 0x53, 0x54].map(header_rom), [72, 80, 96]);
+        assert_eq!(
+            (0x00..=0x05).map(header_ram).collect::<Vec<_>>(),
+            [0, 1, 1, 4, 16, 8]
+        );
+        let mut rom: Vec<u8> = rom(0x00, 0x00);
+        rom[0x0148] = 0x09;
+        rom[0x014D] = header_checksum(&rom);
+        assert!(matches!(
+            CartridgeHeader::parse(&rom),
+            Err(LoadError::BadHeader(_))
+        ));
+    }
+}
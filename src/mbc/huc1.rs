@@ -0,0 +1,172 @@
+use crate::cpu::combine_u8;
+use crate::mbc::ir::{IrLink, NoLink};
+use crate::mbc::{header_ram, header_rom, Mbc, MbcEvent};
+use crate::utils::*;
+
+pub struct HuC1 {
+    rom: Vec<u8>,
+    eram: Vec<u8>,
+    rom_bank: usize, // 6 BITS
+    ram_bank: usize,
+    rom_banks: usize,
+    ir_mode: bool, // 0xA000-0xBFFF maps the IR port instead of RAM
+    led: bool,
+    link: Box<dyn IrLink>,
+    events: Vec<MbcEvent>,
+}
+
+impl Mbc for HuC1 {
+    fn boot(&self) {}
+
+    fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..0x4000 => self.rom[addr as usize],
+            0x4000..0x8000 => self.rom[(addr as usize & 0x3FFF) + self.rom_bank * 0x4000],
+            0xA000..0xC000 if self.ir_mode => 0xC0 | self.link.receive() as u8,
+            0xA000..0xC000 => self.eram_addr(addr).map_or(0xFF, |a| self.eram[a]),
+            _ => 0xFF,
+        }
+    }
+
+    fn read_word(&self, addr: u16) -> u16 {
+        combine_u8(self.read_byte(addr + 1), self.read_byte(addr))
+    }
+
+    fn write_byte(&mut self, addr: u16, b: u8) {
+        match addr {
+            // there's no RAM enable, only the choice between RAM and IR
+            0x0000..0x2000 => self.ir_mode = b & 0x0F == 0x0E,
+            0x2000..0x4000 => {
+                // 6 BITS, bank 0 maps to 1 before the bank wraps to the ROM size
+                let bank: usize = b as usize & 0x3F;
+                self.rom_bank = if bank == 0 {
+                    1
+                } else {
+                    bank & (self.rom_banks - 1)
+                };
+            }
+            0x4000..0x6000 => self.ram_bank = b as usize & 0x03,
+            0x6000..0x8000 => (),
+            0xA000..0xC000 if self.ir_mode => {
+                let led: bool = bit(b, 0);
+                if self.led != led {
+                    self.led = led;
+                    self.link.transmit(self.led);
+                    self.events.push(MbcEvent::Infrared(self.led));
+                }
+            }
+            0xA000..0xC000 => {
+                if let Some(addr) = self.eram_addr(addr) {
+                    self.eram[addr] = b;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn save_data(&self) -> Option<Vec<u8>> {
+        (!self.eram.is_empty()).then(|| self.eram.clone())
+    }
+
+    fn load_save(&mut self, data: &[u8]) {
+        let len: usize = data.len().min(self.eram.len());
+        self.eram[..len].copy_from_slice(&data[..len]);
+    }
+
+    fn take_events(&mut self) -> Vec<MbcEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn set_ir_link(&mut self, link: Box<dyn IrLink>) {
+        self.link = link;
+    }
+}
+
+impl HuC1 {
+    /// HuC1+RAM+BATTERY (0xFF), the only HuC1 cartridge type.
+    pub fn new(data: Vec<u8>) -> Self {
+        let ram_banks: usize = header_ram(data[0x0149]);
+        let rom_banks: usize = header_rom(data[0x0148]);
+        Self {
+            rom: data,
+            eram: vec![0; 0x2000 * ram_banks],
+            rom_bank: 1,
+            ram_bank: 0,
+            rom_banks,
+            ir_mode: false,
+            led: false,
+            link: Box::new(NoLink),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn with_ir_link(mut self, link: Box<dyn IrLink>) -> Self {
+        self.set_ir_link(link);
+        self
+    }
+
+    fn eram_addr(&self, addr: u16) -> Option<usize> {
+        let addr: usize = (addr as usize & 0x1FFF) + self.ram_bank * 0x2000;
+        (addr < self.eram.len()).then_some(addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::banked_rom;
+    use crate::mbc::ir::Loopback;
+
+    fn mbc() -> HuC1 {
+        HuC1::new(banked_rom(0xFF, 0x05, 64))
+    }
+
+    #[test]
+    fn rom_bank() {
+        let mut mbc: HuC1 = mbc();
+        mbc.write_byte(0x2000, 0x3F);
+        assert_eq!(mbc.read_byte(0x4000), 0x3F);
+        mbc.write_byte(0x2000, 0x00);
+        assert_eq!(mbc.read_byte(0x4000), 1);
+        // only zero is remapped, bank 4 wraps to bank 0 on a 4-bank ROM
+        let mut mbc: HuC1 = HuC1::new(banked_rom(0xFF, 0x01, 4));
+        mbc.write_byte(0x2000, 0x04);
+        assert_eq!(mbc.read_byte(0x4000), 0);
+        mbc.write_byte(0x2000, 0x40);
+        assert_eq!(mbc.read_byte(0x4000), 1);
+    }
+
+    #[test]
+    fn ram_banks() {
+        let mut mbc: HuC1 = mbc();
+        // RAM is always readable and writable outside IR mode
+        for bank in 0..4 {
+            mbc.write_byte(0x4000, bank);
+            mbc.write_byte(0xA000, 0x10 | bank);
+        }
+        mbc.write_byte(0x4000, 0x01);
+        assert_eq!(mbc.read_byte(0xA000), 0x11);
+        assert_eq!(mbc.save_data().unwrap()[0x6000], 0x13);
+    }
+
+    #[test]
+    fn infrared() {
+        // attached the way the loader's caller does, through the trait object
+        let mut mbc: Box<dyn Mbc> = Box::new(mbc());
+        mbc.set_ir_link(Box::new(Loopback::default()));
+        mbc.write_byte(0xA000, 0x42);
+        mbc.write_byte(0x0000, 0x0E);
+        assert_eq!(mbc.read_byte(0xA000), 0xC0);
+        mbc.write_byte(0xA000, 0x01);
+        assert_eq!(mbc.read_byte(0xA000), 0xC1);
+        mbc.write_byte(0xA000, 0x01);
+        mbc.write_byte(0xA000, 0x00);
+        assert_eq!(
+            mbc.take_events(),
+            [MbcEvent::Infrared(true), MbcEvent::Infrared(false)]
+        );
+        // leaving IR mode maps the untouched RAM back in
+        mbc.write_byte(0x0000, 0x0A);
+        assert_eq!(mbc.read_byte(0xA000), 0x42);
+    }
+}
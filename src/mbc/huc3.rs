@@ -0,0 +1,332 @@
+use crate::cpu::combine_u8;
+use crate::mbc::ir::{IrLink, NoLink};
+use crate::mbc::rtc::{ClockSource, RtcClock};
+use crate::mbc::{header_ram, header_rom, Mbc, MbcEvent};
+use crate::utils::*;
+
+const RTC_TRAILER: usize = 12;
+const MINUTES_PER_DAY: u64 = 1440;
+
+pub struct HuC3 {
+    rom: Vec<u8>,
+    eram: Vec<u8>,
+    rom_bank: usize, // 7 BITS
+    ram_bank: usize,
+    rom_banks: usize,
+    mode: u8, // selects what 0xA000-0xBFFF maps, see read_byte
+    led: bool,
+    link: Box<dyn IrLink>,
+    rtc: Rtc,
+    events: Vec<MbcEvent>,
+}
+
+impl Mbc for HuC3 {
+    fn boot(&self) {}
+
+    fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..0x4000 => self.rom[addr as usize],
+            0x4000..0x8000 => self.rom[(addr as usize & 0x3FFF) + self.rom_bank * 0x4000],
+            0xA000..0xC000 => match self.mode {
+                // RAM, read-only until 0xA enables writes
+                0x0 | 0xA => self.eram_addr(addr).map_or(0xFF, |a| self.eram[a]),
+                // response to the last RTC command
+                0xC => self.rtc.response,
+                // semaphore, the RTC is always ready
+                0xD => 0x01,
+                0xE => 0xC0 | self.link.receive() as u8,
+                _ => 0xFF,
+            },
+            _ => 0xFF,
+        }
+    }
+
+    fn read_word(&self, addr: u16) -> u16 {
+        combine_u8(self.read_byte(addr + 1), self.read_byte(addr))
+    }
+
+    fn write_byte(&mut self, addr: u16, b: u8) {
+        match addr {
+            0x0000..0x2000 => self.mode = b & 0x0F,
+            0x2000..0x4000 => {
+                // 7 BITS, bank 0 maps to 1 before the bank wraps to the ROM size
+                let bank: usize = b as usize & 0x7F;
+                self.rom_bank = if bank == 0 {
+                    1
+                } else {
+                    bank & (self.rom_banks - 1)
+                };
+            }
+            0x4000..0x6000 => self.ram_bank = b as usize & 0x03,
+            0x6000..0x8000 => (),
+            0xA000..0xC000 => match self.mode {
+                0xA => {
+                    if let Some(addr) = self.eram_addr(addr) {
+                        self.eram[addr] = b;
+                    }
+                }
+                0xB if self.rtc.command(b) => self.events.push(MbcEvent::Tone),
+                0xE if self.led != bit(b, 0) => {
+                    self.led = bit(b, 0);
+                    self.link.transmit(self.led);
+                    self.events.push(MbcEvent::Infrared(self.led));
+                }
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+
+    fn cycle(&mut self, cycles: u16) {
+        self.rtc.clock.cycle(cycles);
+    }
+
+    fn save_data(&self) -> Option<Vec<u8>> {
+        let mut data: Vec<u8> = self.eram.clone();
+        data.extend_from_slice(&self.rtc.trailer());
+        Some(data)
+    }
+
+    fn load_save(&mut self, data: &[u8]) {
+        let len: usize = data.len().min(self.eram.len());
+        self.eram[..len].copy_from_slice(&data[..len]);
+        if data.len() >= len + RTC_TRAILER {
+            self.rtc.load_trailer(&data[len..len + RTC_TRAILER]);
+        }
+    }
+
+    fn take_events(&mut self) -> Vec<MbcEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn set_ir_link(&mut self, link: Box<dyn IrLink>) {
+        self.link = link;
+    }
+}
+
+impl HuC3 {
+    /// HuC3 (0xFE), always with battery-backed RAM and the clock.
+    pub fn new(data: Vec<u8>, source: ClockSource) -> Self {
+        let ram_banks: usize = header_ram(data[0x0149]);
+        let rom_banks: usize = header_rom(data[0x0148]);
+        Self {
+            rom: data,
+            eram: vec![0; 0x2000 * ram_banks],
+            rom_bank: 1,
+            ram_bank: 0,
+            rom_banks,
+            mode: 0,
+            led: false,
+            link: Box::new(NoLink),
+            rtc: Rtc::new(source),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn with_ir_link(mut self, link: Box<dyn IrLink>) -> Self {
+        self.set_ir_link(link);
+        self
+    }
+
+    fn eram_addr(&self, addr: u16) -> Option<usize> {
+        let addr: usize = (addr as usize & 0x1FFF) + self.ram_bank * 0x2000;
+        (addr < self.eram.len()).then_some(addr)
+    }
+}
+
+/// The HuC3 clock counts minutes of the day and days, and is only reachable through
+/// commands that read and write nibbles of a small scratch memory.
+#[derive(Clone)]
+struct Rtc {
+    minutes: u64, // 12 BITS, wraps at 1440
+    days: u64,    // 16 BITS
+    seconds: u64, // not visible to the cartridge
+    mem: [u8; 0x100],
+    index: u8,
+    response: u8,
+    clock: RtcClock,
+}
+
+impl Rtc {
+    fn new(source: ClockSource) -> Self {
+        Self {
+            minutes: 0,
+            days: 0,
+            seconds: 0,
+            mem: [0; 0x100],
+            index: 0,
+            response: 0,
+            clock: RtcClock::new(source),
+        }
+    }
+
+    /// Runs a command written in mode 0xB, returning true if it triggered the tone generator.
+    fn command(&mut self, b: u8) -> bool {
+        let arg: u8 = b & 0x0F;
+        match b >> 4 {
+            // read the nibble at the index, then advance
+            0x1 => {
+                self.response = self.mem[self.index as usize];
+                self.index = self.index.wrapping_add(1);
+            }
+            // write the nibble at the index, 0x3 also advances
+            0x2 => self.mem[self.index as usize] = arg,
+            0x3 => {
+                self.mem[self.index as usize] = arg;
+                self.index = self.index.wrapping_add(1);
+            }
+            0x4 => self.index = (self.index & 0xF0) | arg,
+            0x5 => self.index = (self.index & 0x0F) | arg << 4,
+            0x6 => match arg {
+                // copy the clock into scratch memory 0x00-0x06
+                0x0 => {
+                    self.update();
+                    let time: u64 = self.minutes | self.days << 12;
+                    for i in 0..7 {
+                        self.mem[i] = (time >> (i * 4)) as u8 & 0x0F;
+                    }
+                }
+                // set the clock from scratch memory 0x00-0x06
+                0x1 => {
+                    let time: u64 = (0..7).fold(0, |t, i| t | (self.mem[i] as u64) << (i * 4));
+                    self.update();
+                    self.minutes = (time & 0xFFF) % MINUTES_PER_DAY;
+                    self.days = time >> 12;
+                    self.seconds = 0;
+                    self.clock.reset_subsecond();
+                }
+                0x2 => self.response = 0x01,
+                0xE => return true,
+                _ => (),
+            },
+            _ => (),
+        }
+        false
+    }
+
+    fn update(&mut self) {
+        let secs: u64 = self.seconds + self.clock.elapsed();
+        self.seconds = secs % 60;
+        self.advance(secs / 60);
+    }
+
+    fn advance(&mut self, minutes: u64) {
+        let total: u64 = self.minutes + minutes;
+        self.minutes = total % MINUTES_PER_DAY;
+        self.days = (self.days + total / MINUTES_PER_DAY) & 0xFFFF;
+    }
+
+    /// Minutes and days as little-endian u16s, then the unix time they were saved at.
+    fn trailer(&self) -> [u8; RTC_TRAILER] {
+        let rtc: Rtc = RtcClock::snapshot(self, Rtc::update);
+        let mut trailer: [u8; RTC_TRAILER] = [0; RTC_TRAILER];
+        trailer[0..2].copy_from_slice(&(rtc.minutes as u16).to_le_bytes());
+        trailer[2..4].copy_from_slice(&(rtc.days as u16).to_le_bytes());
+        trailer[4..].copy_from_slice(&rtc.clock.timestamp().to_le_bytes());
+        trailer
+    }
+
+    fn load_trailer(&mut self, trailer: &[u8]) {
+        self.minutes = u16::from_le_bytes([trailer[0], trailer[1]]) as u64 % MINUTES_PER_DAY;
+        self.days = u16::from_le_bytes([trailer[2], trailer[3]]) as u64;
+        let timestamp: u64 = u64::from_le_bytes(trailer[4..12].try_into().unwrap());
+        let secs: u64 = self.clock.since(timestamp);
+        self.advance(secs / 60);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::banked_rom;
+    use crate::CLOCK_SPEED;
+
+    fn mbc() -> HuC3 {
+        HuC3::new(banked_rom(0xFE, 0x02, 8), ClockSource::Cycles)
+    }
+
+    fn command(mbc: &mut HuC3, b: u8) -> u8 {
+        mbc.write_byte(0x0000, 0x0B);
+        mbc.write_byte(0xA000, b);
+        mbc.write_byte(0x0000, 0x0C);
+        mbc.read_byte(0xA000)
+    }
+
+    /// Copies the clock to scratch memory and reads back minutes and days.
+    fn read_clock(mbc: &mut HuC3) -> (u64, u64) {
+        command(mbc, 0x60);
+        command(mbc, 0x40);
+        command(mbc, 0x50);
+        let time: u64 = (0..7).fold(0, |t, i| t | (command(mbc, 0x10) as u64 & 0x0F) << (i * 4));
+        (time & 0xFFF, time >> 12)
+    }
+
+    fn run_secs(mbc: &mut HuC3, secs: u32) {
+        for _ in 0..secs * CLOCK_SPEED / 0x8000 {
+            mbc.cycle(0x8000);
+        }
+    }
+
+    #[test]
+    fn ram_write_mode() {
+        let mut mbc: HuC3 = mbc();
+        mbc.write_byte(0x4000, 0x02);
+        mbc.write_byte(0xA000, 0x42);
+        assert_eq!(mbc.read_byte(0xA000), 0x00);
+        mbc.write_byte(0x0000, 0x0A);
+        mbc.write_byte(0xA000, 0x42);
+        mbc.write_byte(0x0000, 0x00);
+        assert_eq!(mbc.read_byte(0xA000), 0x42);
+        mbc.write_byte(0x2000, 0x05);
+        assert_eq!(mbc.read_byte(0x4000), 5);
+    }
+
+    #[test]
+    fn rom_bank() {
+        let mut mbc: HuC3 = mbc();
+        mbc.write_byte(0x2000, 0x00);
+        assert_eq!(mbc.read_byte(0x4000), 1);
+        // only zero is remapped, bank 8 wraps to bank 0 on an 8-bank ROM
+        mbc.write_byte(0x2000, 0x08);
+        assert_eq!(mbc.read_byte(0x4000), 0);
+        mbc.write_byte(0x2000, 0x80);
+        assert_eq!(mbc.read_byte(0x4000), 1);
+    }
+
+    #[test]
+    fn set_and_read_clock() {
+        let mut mbc: HuC3 = mbc();
+        // 23:59 on day 3
+        command(&mut mbc, 0x40);
+        command(&mut mbc, 0x50);
+        for nibble in [0xF, 0x9, 0x5, 0x3, 0x0, 0x0, 0x0] {
+            command(&mut mbc, 0x30 | nibble);
+        }
+        command(&mut mbc, 0x61);
+        mbc.write_byte(0x0000, 0x0D);
+        assert_eq!(mbc.read_byte(0xA000), 0x01);
+        assert_eq!(read_clock(&mut mbc), (1439, 3));
+        run_secs(&mut mbc, 60);
+        assert_eq!(read_clock(&mut mbc), (0, 4));
+    }
+
+    #[test]
+    fn rtc_trailer() {
+        let mut mbc: HuC3 = mbc();
+        run_secs(&mut mbc, 150);
+        assert_eq!(read_clock(&mut mbc), (2, 0));
+        let save: Vec<u8> = mbc.save_data().unwrap();
+        assert_eq!(save.len(), 4 * 0x2000 + RTC_TRAILER);
+        assert_eq!(save[4 * 0x2000..4 * 0x2000 + 4], [2, 0, 0, 0]);
+        let mut loaded: HuC3 = self::mbc();
+        loaded.load_save(&save);
+        assert_eq!(read_clock(&mut loaded), (2, 0));
+    }
+
+    #[test]
+    fn tone() {
+        let mut mbc: HuC3 = mbc();
+        command(&mut mbc, 0x6E);
+        assert_eq!(mbc.take_events(), [MbcEvent::Tone]);
+    }
+}
@@ -0,0 +1,84 @@
+/// Width and height of the M64282FP sensor image the Pocket Camera keeps.
+pub const SENSOR_WIDTH: usize = 128;
+pub const SENSOR_HEIGHT: usize = 112;
+
+pub type SensorImage = [u8; SENSOR_WIDTH * SENSOR_HEIGHT];
+
+/// Supplies the light falling on the Pocket Camera's sensor, one 8-bit greyscale frame
+/// per capture with 0 for black and 255 for white.
+pub trait ImageSource: Send {
+    fn capture(&mut self, image: &mut SensorImage);
+}
+
+/// Any frame callback is a source.
+impl<F: FnMut(&mut SensorImage) + Send> ImageSource for F {
+    fn capture(&mut self, image: &mut SensorImage) {
+        self(image)
+    }
+}
+
+/// Diagonal gradient bars that scroll one pixel per capture, so exposure and dithering
+/// settings are visible without a real image.
+#[derive(Default)]
+pub struct TestPattern {
+    frame: usize,
+}
+
+impl ImageSource for TestPattern {
+    fn capture(&mut self, image: &mut SensorImage) {
+        for y in 0..SENSOR_HEIGHT {
+            for x in 0..SENSOR_WIDTH {
+                image[y * SENSOR_WIDTH + x] = ((x + y + self.frame) % 64 * 4) as u8;
+            }
+        }
+        self.frame = self.frame.wrapping_add(1);
+    }
+}
+
+/// The same picture every capture.
+pub struct StaticImage {
+    image: SensorImage,
+}
+
+impl StaticImage {
+    /// Scales a greyscale image of any size to the sensor with nearest neighbour sampling.
+    pub fn new(pixels: &[u8], width: usize, height: usize) -> Self {
+        let mut image: SensorImage = [0; SENSOR_WIDTH * SENSOR_HEIGHT];
+        if width > 0 && height > 0 && pixels.len() >= width * height {
+            for y in 0..SENSOR_HEIGHT {
+                for x in 0..SENSOR_WIDTH {
+                    let sx: usize = x * width / SENSOR_WIDTH;
+                    let sy: usize = y * height / SENSOR_HEIGHT;
+                    image[y * SENSOR_WIDTH + x] = pixels[sy * width + sx];
+                }
+            }
+        }
+        Self { image }
+    }
+
+    /// Decodes a PNG and converts it to greyscale.
+    #[cfg(feature = "png")]
+    pub fn from_png(path: &str) -> std::io::Result<Self> {
+        let mut decoder = png::Decoder::new(std::fs::File::open(path)?);
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let mut reader = decoder.read_info().map_err(std::io::Error::other)?;
+        let mut buf: Vec<u8> = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf).map_err(std::io::Error::other)?;
+        let channels: usize = info.color_type.samples();
+        let luma: Vec<u8> = buf[..info.buffer_size()]
+            .chunks_exact(channels)
+            .map(|px| match channels {
+                1 | 2 => px[0],
+                // ITU-R BT.601 luma
+                _ => ((px[0] as u32 * 299 + px[1] as u32 * 587 + px[2] as u32 * 114) / 1000) as u8,
+            })
+            .collect();
+        Ok(Self::new(&luma, info.width as usize, info.height as usize))
+    }
+}
+
+impl ImageSource for StaticImage {
+    fn capture(&mut self, image: &mut SensorImage) {
+        image.copy_from_slice(&self.image);
+    }
+}
@@ -0,0 +1,36 @@
+/// The other end of a cartridge's infrared port. The cartridge's own LED is reported
+/// to the frontend as `MbcEvent::Infrared`; a link decides what its receiver sees.
+pub trait IrLink: Send {
+    /// Called whenever the cartridge switches its LED on or off.
+    fn transmit(&mut self, led: bool);
+
+    /// True while light falls on the cartridge's receiver.
+    fn receive(&self) -> bool;
+}
+
+/// Nothing on the other end, the receiver stays dark.
+pub struct NoLink;
+
+impl IrLink for NoLink {
+    fn transmit(&mut self, _led: bool) {}
+
+    fn receive(&self) -> bool {
+        false
+    }
+}
+
+/// Reflects the cartridge's own LED back into its receiver.
+#[derive(Default)]
+pub struct Loopback {
+    led: bool,
+}
+
+impl IrLink for Loopback {
+    fn transmit(&mut self, led: bool) {
+        self.led = led;
+    }
+
+    fn receive(&self) -> bool {
+        self.led
+    }
+}
@@ -9,10 +9,13 @@ impl Mbc for Mbc0 {
     fn boot(&self) {}
 
     fn read_byte(&self, addr: u16) -> u8 {
-        self.data[addr as usize]
+        match addr {
+            0x0000..0x8000 => self.data[addr as usize],
+            _ => 0xFF, // no cartridge RAM
+        }
     }
     fn read_word(&self, addr: u16) -> u16 {
-        combine_u8(self.data[addr as usize + 1], self.data[addr as usize])
+        combine_u8(self.read_byte(addr + 1), self.read_byte(addr))
     }
     fn write_byte(&mut self, addr: u16, b: u8) {
         ()
@@ -1,3 +1,4 @@
+use crate::mbc::header::has_logo;
 use crate::mbc::{header_ram, header_rom, Mbc};
 
 pub struct Mbc1 {
@@ -10,6 +11,7 @@ pub struct Mbc1 {
     rom_banks: usize,
     ram_banks: usize,
     battery: bool,
+    multicart: bool,
 }
 
 impl Mbc for Mbc1 {
@@ -20,10 +22,13 @@ impl Mbc for Mbc1 {
     fn read_byte(&self, addr: u16) -> u8 {
         match addr {
             0x0000..0x8000 => self.rom[self.translate(addr)],
-            0xA000..0xC000 => self
-                .ram_enable
-                .then(|| self.eram[self.translate(addr)])
-                .unwrap_or(0xFF),
+            0xA000..0xC000 => {
+                if self.ram_enable && !self.eram.is_empty() {
+                    self.eram[self.translate(addr)]
+                } else {
+                    0xFF
+                }
+            }
             _ => todo!("UNSUPPORTED READ 0x{:04X}", addr),
         }
     }
@@ -58,7 +63,7 @@ impl Mbc for Mbc1 {
             }
             0x6000..0x8000 => self.mode = b & 1 == 1,
             0xA000..0xC000 => {
-                if self.ram_enable {
+                if self.ram_enable && !self.eram.is_empty() {
                     let addr: usize = self.translate(addr);
                     self.eram[addr] = b;
                 }
@@ -66,6 +71,15 @@ impl Mbc for Mbc1 {
             _ => todo!("UNSUPPORTED WRITE 0x{:04X}", addr),
         }
     }
+
+    fn save_data(&self) -> Option<Vec<u8>> {
+        (self.battery && !self.eram.is_empty()).then(|| self.eram.clone())
+    }
+
+    fn load_save(&mut self, data: &[u8]) {
+        let len: usize = data.len().min(self.eram.len());
+        self.eram[..len].copy_from_slice(&data[..len]);
+    }
 }
 
 impl Mbc1 {
@@ -77,6 +91,9 @@ impl Mbc1 {
             _ => panic!(),
         };
         let rom_banks: usize = header_rom(data[0x0148]);
+        // MBC1M carts are 1 MiB collections of 256 KiB games, each with its own header.
+        // No header field marks them, so look for a second logo at a game boundary.
+        let multicart: bool = rom_banks == 64 && (1..4).any(|game| has_logo(&data, game * 0x40000));
         Self {
             rom: data,
             eram: vec![0; 0x2000 * ram_banks],
@@ -87,6 +104,7 @@ impl Mbc1 {
             rom_banks,
             ram_banks,
             battery,
+            multicart,
         }
     }
     fn translate(&self, addr: u16) -> usize {
@@ -101,7 +119,7 @@ impl Mbc1 {
                 } else {
                     self.rom_bank
                 };
-                (addr as usize & 0x3FFF) + (bank * 0x4000)
+                (addr as usize & 0x3FFF) + (self.rom_bank_number(bank) * 0x4000)
             }
             0xA000..0xC000 => {
                 let bank: usize = if self.mode { self.ram_bank } else { 0 };
@@ -110,4 +128,79 @@ impl Mbc1 {
             _ => panic!(),
         }
     }
+
+    /// MBC1M wires the upper bank register to bits 4-5 and drops bit 4 of the lower one.
+    /// Bank lines the ROM doesn't have are ignored, so the bank wraps.
+    fn rom_bank_number(&self, bank: usize) -> usize {
+        let bank: usize = if self.multicart {
+            (bank & 0x60) >> 1 | bank & 0x0F
+        } else {
+            bank
+        };
+        bank & (self.rom_banks - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::banked_rom;
+    use crate::mbc::header::NINTENDO_LOGO;
+
+    #[test]
+    fn bank_zero_is_one() {
+        let mut mbc: Mbc1 = Mbc1::new(banked_rom(0x01, 0x02, 8));
+        mbc.write_byte(0x2000, 0x00);
+        assert_eq!(mbc.read_byte(0x4000), 1);
+        // a 128 KiB ROM only decodes three bank bits
+        mbc.write_byte(0x2000, 0x0D);
+        assert_eq!(mbc.read_byte(0x4000), 5);
+    }
+
+    #[test]
+    fn upper_bits_masked() {
+        let mut mbc: Mbc1 = Mbc1::new(banked_rom(0x01, 0x05, 64));
+        mbc.write_byte(0x4000, 0x03);
+        mbc.write_byte(0x2000, 0x1F);
+        assert_eq!(mbc.read_byte(0x4000), 0x3F);
+        assert_eq!(mbc.read_byte(0x0000), 0x00);
+        mbc.write_byte(0x6000, 0x01);
+        assert_eq!(mbc.read_byte(0x0000), 0x20);
+        assert_eq!(mbc.read_byte(0xA000), 0xFF);
+        mbc.write_byte(0x0000, 0x0A);
+        mbc.write_byte(0xA000, 0x12);
+        assert_eq!(mbc.read_byte(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn multicart() {
+        let mut rom: Vec<u8> = banked_rom(0x01, 0x05, 64);
+        for game in 0..4 {
+            rom[game * 0x40000 + 0x0104..game * 0x40000 + 0x0134].copy_from_slice(&NINTENDO_LOGO);
+        }
+        let mut mbc: Mbc1 = Mbc1::new(rom);
+        assert!(mbc.multicart);
+        mbc.write_byte(0x4000, 0x01);
+        mbc.write_byte(0x2000, 0x12);
+        assert_eq!(mbc.read_byte(0x4000), 0x12);
+        mbc.write_byte(0x6000, 0x01);
+        assert_eq!(mbc.read_byte(0x0000), 0x10);
+    }
+
+    #[test]
+    fn ram_banks() {
+        let mut mbc: Mbc1 = Mbc1::new(banked_rom(0x03, 0x02, 8));
+        mbc.write_byte(0x0000, 0x0A);
+        mbc.write_byte(0x6000, 0x01);
+        for bank in 0..4 {
+            mbc.write_byte(0x4000, bank);
+            mbc.write_byte(0xA000, 0x10 | bank);
+        }
+        mbc.write_byte(0x4000, 0x02);
+        assert_eq!(mbc.read_byte(0xA000), 0x12);
+        // mode 0 pins RAM to bank 0
+        mbc.write_byte(0x6000, 0x00);
+        assert_eq!(mbc.read_byte(0xA000), 0x10);
+        assert_eq!(mbc.save_data().unwrap()[0x2000], 0x11);
+    }
 }
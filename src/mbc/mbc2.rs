@@ -0,0 +1,133 @@
+use crate::cpu::combine_u8;
+use crate::mbc::{header_rom, Mbc};
+use crate::utils::*;
+
+pub struct Mbc2 {
+    rom: Vec<u8>,
+    ram: Vec<u8>, // 512 x 4 BITS
+    rom_bank: usize,
+    ram_enable: bool,
+    rom_banks: usize,
+    battery: bool,
+}
+
+impl Mbc for Mbc2 {
+    fn boot(&self) {}
+
+    fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..0x4000 => self.rom[addr as usize],
+            0x4000..0x8000 => self.rom[(addr as usize & 0x3FFF) + self.rom_bank * 0x4000],
+            // only the low nibble exists, and the 512 bytes echo through 0xA000-0xBFFF
+            0xA000..0xC000 if self.ram_enable => self.ram[addr as usize & 0x1FF] | 0xF0,
+            _ => 0xFF,
+        }
+    }
+
+    fn read_word(&self, addr: u16) -> u16 {
+        combine_u8(self.read_byte(addr + 1), self.read_byte(addr))
+    }
+
+    fn write_byte(&mut self, addr: u16, b: u8) {
+        match addr {
+            // address bit 8 selects between the two registers
+            0x0000..0x4000 => {
+                if bit(addr, 8) {
+                    // 4 BITS, bank 0 maps to 1 before the bank wraps to the ROM size
+                    let bank: usize = (b & 0x0F) as usize;
+                    self.rom_bank = if bank == 0 {
+                        1
+                    } else {
+                        bank & (self.rom_banks - 1)
+                    };
+                } else {
+                    self.ram_enable = b & 0x0F == 0x0A;
+                }
+            }
+            0x4000..0x8000 => (),
+            0xA000..0xC000 if self.ram_enable => {
+                self.ram[addr as usize & 0x1FF] = b & 0x0F;
+            }
+            _ => (),
+        }
+    }
+
+    fn save_data(&self) -> Option<Vec<u8>> {
+        self.battery.then(|| self.ram.clone())
+    }
+
+    fn load_save(&mut self, data: &[u8]) {
+        for (v, b) in self.ram.iter_mut().zip(data) {
+            *v = b & 0x0F;
+        }
+    }
+}
+
+impl Mbc2 {
+    pub fn new(data: Vec<u8>) -> Self {
+        let battery: bool = match data[0x0147] {
+            0x05 => false,
+            0x06 => true,
+            _ => panic!(),
+        };
+        let rom_banks: usize = header_rom(data[0x0148]);
+        Self {
+            rom: data,
+            ram: vec![0; 0x200],
+            rom_bank: 1,
+            ram_enable: false,
+            rom_banks,
+            battery,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::banked_rom;
+
+    fn mbc() -> Mbc2 {
+        Mbc2::new(banked_rom(0x06, 0x03, 16))
+    }
+
+    #[test]
+    fn rom_bank() {
+        let mut mbc: Mbc2 = mbc();
+        mbc.write_byte(0x2100, 0x07);
+        assert_eq!(mbc.read_byte(0x4000), 7);
+        mbc.write_byte(0x2100, 0x00);
+        assert_eq!(mbc.read_byte(0x4000), 1);
+        // with address bit 8 clear the write goes to the RAM enable instead
+        mbc.write_byte(0x2000, 0x05);
+        assert_eq!(mbc.read_byte(0x4000), 1);
+    }
+
+    #[test]
+    fn small_rom_wraps() {
+        let mut mbc: Mbc2 = Mbc2::new(banked_rom(0x06, 0x01, 4));
+        // only zero is remapped, bank 4 wraps to bank 0 on a 4-bank ROM
+        mbc.write_byte(0x2100, 0x04);
+        assert_eq!(mbc.read_byte(0x4000), 0);
+        mbc.write_byte(0x2100, 0x10);
+        assert_eq!(mbc.read_byte(0x4000), 1);
+    }
+
+    #[test]
+    fn nibble_ram() {
+        let mut mbc: Mbc2 = mbc();
+        mbc.write_byte(0xA000, 0x05);
+        assert_eq!(mbc.read_byte(0xA000), 0xFF);
+        mbc.write_byte(0x0100, 0x0A);
+        assert_eq!(mbc.read_byte(0xA000), 0xFF);
+        mbc.write_byte(0x0000, 0x0A);
+        mbc.write_byte(0xA000, 0xA5);
+        mbc.write_byte(0xA1FF, 0x3C);
+        assert_eq!(mbc.read_byte(0xA000), 0xF5);
+        // the 512 nibbles repeat every 0x200 bytes
+        assert_eq!(mbc.read_byte(0xA200), 0xF5);
+        assert_eq!(mbc.read_byte(0xBFFF), 0xFC);
+        let save: Vec<u8> = mbc.save_data().unwrap();
+        assert_eq!((save.len(), save[0], save[0x1FF]), (0x200, 0x05, 0x0C));
+    }
+}
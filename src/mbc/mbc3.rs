@@ -0,0 +1,393 @@
+use crate::cpu::combine_u8;
+use crate::mbc::rtc::{ClockSource, RtcClock};
+use crate::mbc::{header_ram, header_rom, Mbc};
+use crate::utils::*;
+
+const RTC_TRAILER: usize = 48;
+
+pub struct Mbc3 {
+    rom: Vec<u8>,
+    eram: Vec<u8>,
+    rom_bank: usize,
+    ram_bank: u8, // 0x00-0x07 RAM, 0x08-0x0C RTC registers
+    ram_enable: bool,
+    rom_banks: usize,
+    latch: u8,
+    rtc: Option<Rtc>,
+    battery: bool,
+}
+
+impl Mbc for Mbc3 {
+    fn boot(&self) {}
+
+    fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..0x4000 => self.rom[addr as usize],
+            0x4000..0x8000 => self.rom[(addr as usize & 0x3FFF) + self.rom_bank * 0x4000],
+            0xA000..0xC000 => {
+                if !self.ram_enable {
+                    return 0xFF;
+                }
+                match (self.ram_bank, self.rtc.as_ref()) {
+                    (0x00..0x08, _) => self.eram_addr(addr).map_or(0xFF, |a| self.eram[a]),
+                    (0x08..0x0D, Some(rtc)) => rtc.latched[self.ram_bank as usize - 0x08],
+                    _ => 0xFF,
+                }
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn read_word(&self, addr: u16) -> u16 {
+        combine_u8(self.read_byte(addr + 1), self.read_byte(addr))
+    }
+
+    fn write_byte(&mut self, addr: u16, b: u8) {
+        match addr {
+            0x0000..0x2000 => self.ram_enable = b & 0x0F == 0x0A,
+            0x2000..0x4000 => {
+                // 7 BITS (8 on MBC30), bank 0 maps to 1 before the bank wraps to the ROM size
+                let bits: u8 = if self.rom_banks > 0x80 { 0xFF } else { 0x7F };
+                let bank: usize = (b & bits) as usize;
+                self.rom_bank = if bank == 0 {
+                    1
+                } else {
+                    bank & (self.rom_banks - 1)
+                };
+            }
+            0x4000..0x6000 => self.ram_bank = b & 0x0F,
+            0x6000..0x8000 => {
+                // writing 0x00 then 0x01 latches the clock into the readable registers
+                if let Some(rtc) = self.rtc.as_mut() {
+                    if self.latch == 0x00 && b == 0x01 {
+                        rtc.update();
+                        rtc.latched = rtc.regs;
+                    }
+                }
+                self.latch = b;
+            }
+            0xA000..0xC000 => {
+                if !self.ram_enable {
+                    return;
+                }
+                match self.ram_bank {
+                    0x00..0x08 => {
+                        if let Some(addr) = self.eram_addr(addr) {
+                            self.eram[addr] = b;
+                        }
+                    }
+                    0x08..0x0D => {
+                        if let Some(rtc) = self.rtc.as_mut() {
+                            rtc.write(self.ram_bank as usize - 0x08, b);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn cycle(&mut self, cycles: u16) {
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.clock.cycle(cycles);
+        }
+    }
+
+    fn save_data(&self) -> Option<Vec<u8>> {
+        if !self.battery {
+            return None;
+        }
+        let mut data: Vec<u8> = self.eram.clone();
+        if let Some(rtc) = self.rtc.as_ref() {
+            data.extend_from_slice(&rtc.trailer());
+        }
+        Some(data)
+    }
+
+    fn load_save(&mut self, data: &[u8]) {
+        let len: usize = data.len().min(self.eram.len());
+        self.eram[..len].copy_from_slice(&data[..len]);
+        if let Some(rtc) = self.rtc.as_mut() {
+            if data.len() >= len + RTC_TRAILER {
+                rtc.load_trailer(&data[len..len + RTC_TRAILER]);
+            }
+        }
+    }
+}
+
+impl Mbc3 {
+    pub fn new(data: Vec<u8>, source: ClockSource) -> Self {
+        let (rtc, battery, ram_banks) = match data[0x0147] {
+            0x0F => (true, true, 0),
+            0x10 => (true, true, header_ram(data[0x0149])),
+            0x11 => (false, false, 0),
+            0x12 => (false, false, header_ram(data[0x0149])),
+            0x13 => (false, true, header_ram(data[0x0149])),
+            _ => panic!(),
+        };
+        let rom_banks: usize = header_rom(data[0x0148]);
+        Self {
+            rom: data,
+            eram: vec![0; 0x2000 * ram_banks],
+            rom_bank: 1,
+            ram_bank: 0,
+            ram_enable: false,
+            rom_banks,
+            latch: 0xFF,
+            rtc: rtc.then(|| Rtc::new(source)),
+            battery,
+        }
+    }
+
+    fn eram_addr(&self, addr: u16) -> Option<usize> {
+        let addr: usize = (addr as usize & 0x1FFF) + self.ram_bank as usize * 0x2000;
+        (addr < self.eram.len()).then_some(addr)
+    }
+}
+
+#[derive(Clone)]
+struct Rtc {
+    regs: [u8; 5], // S, M, H, DL, DH
+    latched: [u8; 5],
+    clock: RtcClock,
+}
+
+impl Rtc {
+    fn new(source: ClockSource) -> Self {
+        Self {
+            regs: [0; 5],
+            latched: [0; 5],
+            clock: RtcClock::new(source),
+        }
+    }
+
+    fn halted(&self) -> bool {
+        bit(self.regs[4], 6)
+    }
+
+    fn update(&mut self) {
+        let secs: u64 = self.clock.elapsed();
+        if !self.halted() {
+            self.advance(secs);
+        }
+    }
+
+    fn write(&mut self, reg: usize, b: u8) {
+        self.update();
+        self.regs[reg] = match reg {
+            0 => {
+                self.clock.reset_subsecond();
+                b & 0x3F
+            }
+            1 => b & 0x3F,
+            2 => b & 0x1F,
+            3 => b,
+            4 => b & 0xC1,
+            _ => unreachable!(),
+        };
+    }
+
+    fn days(&self) -> u64 {
+        self.regs[3] as u64 | ((self.regs[4] as u64 & 1) << 8)
+    }
+
+    fn set_days(&mut self, days: u64) {
+        if days > 0x1FF {
+            self.regs[4] |= 0x80; // day counter carry, sticky until written
+        }
+        self.regs[3] = days as u8;
+        self.regs[4] = (self.regs[4] & 0xFE) | ((days >> 8) & 1) as u8;
+    }
+
+    fn advance(&mut self, mut secs: u64) {
+        // registers written out of range roll over the slow way before they're valid again
+        while secs > 0 && (self.regs[0] >= 60 || self.regs[1] >= 60 || self.regs[2] >= 24) {
+            self.tick();
+            secs -= 1;
+        }
+        if secs == 0 {
+            return;
+        }
+        let total: u64 = self.regs[0] as u64
+            + self.regs[1] as u64 * 60
+            + self.regs[2] as u64 * 3600
+            + self.days() * 86400
+            + secs;
+        self.regs[0] = (total % 60) as u8;
+        self.regs[1] = (total / 60 % 60) as u8;
+        self.regs[2] = (total / 3600 % 24) as u8;
+        let days: u64 = total / 86400;
+        self.set_days(days);
+    }
+
+    fn tick(&mut self) {
+        self.regs[0] = (self.regs[0] + 1) & 0x3F;
+        if self.regs[0] != 60 {
+            return;
+        }
+        self.regs[0] = 0;
+        self.regs[1] = (self.regs[1] + 1) & 0x3F;
+        if self.regs[1] != 60 {
+            return;
+        }
+        self.regs[1] = 0;
+        self.regs[2] = (self.regs[2] + 1) & 0x1F;
+        if self.regs[2] != 24 {
+            return;
+        }
+        self.regs[2] = 0;
+        let days: u64 = self.days() + 1;
+        self.set_days(days);
+    }
+
+    /// The 48-byte trailer shared by most emulators: live and latched registers as
+    /// little-endian u32s, then the unix time they were saved at as a u64.
+    fn trailer(&self) -> [u8; RTC_TRAILER] {
+        let rtc: Rtc = RtcClock::snapshot(self, Rtc::update);
+        let mut trailer: [u8; RTC_TRAILER] = [0; RTC_TRAILER];
+        for (i, v) in rtc.regs.iter().chain(rtc.latched.iter()).enumerate() {
+            trailer[i * 4] = *v;
+        }
+        trailer[40..].copy_from_slice(&rtc.clock.timestamp().to_le_bytes());
+        trailer
+    }
+
+    fn load_trailer(&mut self, trailer: &[u8]) {
+        let masks: [u8; 5] = [0x3F, 0x3F, 0x1F, 0xFF, 0xC1];
+        for i in 0..5 {
+            self.regs[i] = trailer[i * 4] & masks[i];
+            self.latched[i] = trailer[20 + i * 4] & masks[i];
+        }
+        let timestamp: u64 = u64::from_le_bytes(trailer[40..48].try_into().unwrap());
+        let secs: u64 = self.clock.since(timestamp);
+        if !self.halted() {
+            self.advance(secs);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::banked_rom;
+    use crate::CLOCK_SPEED;
+
+    fn mbc() -> Mbc3 {
+        let mut mbc: Mbc3 = Mbc3::new(banked_rom(0x10, 0x02, 8), ClockSource::Cycles);
+        mbc.write_byte(0x0000, 0x0A);
+        mbc
+    }
+
+    fn run_secs(mbc: &mut Mbc3, secs: u64) {
+        for _ in 0..secs * CLOCK_SPEED as u64 / 0x8000 {
+            mbc.cycle(0x8000);
+        }
+    }
+
+    fn latch(mbc: &mut Mbc3) {
+        mbc.write_byte(0x6000, 0x00);
+        mbc.write_byte(0x6000, 0x01);
+    }
+
+    fn read_rtc(mbc: &mut Mbc3, reg: u8) -> u8 {
+        mbc.write_byte(0x4000, reg);
+        mbc.read_byte(0xA000)
+    }
+
+    fn write_rtc(mbc: &mut Mbc3, reg: u8, b: u8) {
+        mbc.write_byte(0x4000, reg);
+        mbc.write_byte(0xA000, b);
+    }
+
+    #[test]
+    fn rom_bank() {
+        let mut mbc: Mbc3 = Mbc3::new(banked_rom(0x11, 0x01, 4), ClockSource::Cycles);
+        mbc.write_byte(0x2000, 0x00);
+        assert_eq!(mbc.read_byte(0x4000), 1);
+        // only zero is remapped, bank 4 wraps to bank 0 on a 4-bank ROM
+        mbc.write_byte(0x2000, 0x04);
+        assert_eq!(mbc.read_byte(0x4000), 0);
+        // bit 7 isn't wired on MBC3, so 0x80 is bank 0 too
+        mbc.write_byte(0x2000, 0x80);
+        assert_eq!(mbc.read_byte(0x4000), 1);
+    }
+
+    #[test]
+    fn latching() {
+        let mut mbc: Mbc3 = mbc();
+        run_secs(&mut mbc, 61);
+        assert_eq!(read_rtc(&mut mbc, 0x08), 0);
+        latch(&mut mbc);
+        assert_eq!((read_rtc(&mut mbc, 0x08), read_rtc(&mut mbc, 0x09)), (1, 1));
+        // the latched copy holds still until the next 0 -> 1 write
+        run_secs(&mut mbc, 5);
+        mbc.write_byte(0x6000, 0x01);
+        assert_eq!(read_rtc(&mut mbc, 0x08), 1);
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, 0x08), 6);
+    }
+
+    #[test]
+    fn halt() {
+        let mut mbc: Mbc3 = mbc();
+        write_rtc(&mut mbc, 0x0C, 0x40);
+        run_secs(&mut mbc, 10);
+        latch(&mut mbc);
+        assert_eq!(
+            (read_rtc(&mut mbc, 0x08), read_rtc(&mut mbc, 0x0C)),
+            (0, 0x40)
+        );
+        write_rtc(&mut mbc, 0x0C, 0x00);
+        run_secs(&mut mbc, 10);
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, 0x08), 10);
+    }
+
+    #[test]
+    fn day_carry() {
+        let mut mbc: Mbc3 = mbc();
+        // 23:59:59 on day 511
+        write_rtc(&mut mbc, 0x0A, 23);
+        write_rtc(&mut mbc, 0x09, 59);
+        write_rtc(&mut mbc, 0x08, 59);
+        write_rtc(&mut mbc, 0x0B, 0xFF);
+        write_rtc(&mut mbc, 0x0C, 0x01);
+        run_secs(&mut mbc, 1);
+        latch(&mut mbc);
+        let regs: Vec<u8> = (0x08..0x0D).map(|reg| read_rtc(&mut mbc, reg)).collect();
+        assert_eq!(regs, [0, 0, 0, 0, 0x80]);
+        // the carry stays set until the game clears it
+        run_secs(&mut mbc, 86400);
+        latch(&mut mbc);
+        assert_eq!(
+            (read_rtc(&mut mbc, 0x0B), read_rtc(&mut mbc, 0x0C)),
+            (1, 0x80)
+        );
+        write_rtc(&mut mbc, 0x0C, 0x00);
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, 0x0C), 0x00);
+    }
+
+    #[test]
+    fn rtc_trailer() {
+        let mut mbc: Mbc3 = mbc();
+        write_rtc(&mut mbc, 0x0A, 5);
+        latch(&mut mbc);
+        run_secs(&mut mbc, 3);
+        mbc.write_byte(0x4000, 0x00);
+        mbc.write_byte(0xA000, 0x42);
+        let save: Vec<u8> = mbc.save_data().unwrap();
+        let trailer: &[u8] = &save[4 * 0x2000..];
+        assert_eq!(trailer.len(), RTC_TRAILER);
+        // live registers first, then the latched ones, each a little-endian u32
+        assert_eq!(trailer[..12], [3, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0]);
+        assert_eq!(trailer[20..32], [0, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0]);
+        let mut loaded: Mbc3 = self::mbc();
+        loaded.load_save(&save);
+        assert_eq!(loaded.read_byte(0xA000), 0x42);
+        assert_eq!(read_rtc(&mut loaded, 0x0A), 5);
+        latch(&mut loaded);
+        assert_eq!(read_rtc(&mut loaded, 0x08), 3);
+    }
+}
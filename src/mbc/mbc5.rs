@@ -0,0 +1,169 @@
+use crate::cpu::combine_u8;
+use crate::mbc::{header_ram, header_rom, Mbc, MbcEvent};
+use crate::utils::*;
+
+pub struct Mbc5 {
+    rom: Vec<u8>,
+    eram: Vec<u8>,
+    rom_bank: usize, // 9 BITS, bank 0 is selectable
+    ram_bank: usize,
+    ram_enable: bool,
+    rom_banks: usize,
+    rumble: Option<bool>, // motor state on rumble carts
+    battery: bool,
+    events: Vec<MbcEvent>,
+}
+
+impl Mbc for Mbc5 {
+    fn boot(&self) {}
+
+    fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..0x4000 => self.rom[addr as usize],
+            0x4000..0x8000 => {
+                let bank: usize = self.rom_bank & (self.rom_banks - 1);
+                self.rom[(addr as usize & 0x3FFF) + bank * 0x4000]
+            }
+            0xA000..0xC000 => self
+                .eram_addr(addr)
+                .filter(|_| self.ram_enable)
+                .map_or(0xFF, |a| self.eram[a]),
+            _ => 0xFF,
+        }
+    }
+
+    fn read_word(&self, addr: u16) -> u16 {
+        combine_u8(self.read_byte(addr + 1), self.read_byte(addr))
+    }
+
+    fn write_byte(&mut self, addr: u16, b: u8) {
+        match addr {
+            0x0000..0x2000 => self.ram_enable = b == 0x0A,
+            0x2000..0x3000 => self.rom_bank = (self.rom_bank & 0x100) | b as usize,
+            0x3000..0x4000 => self.rom_bank = (self.rom_bank & 0xFF) | (b as usize & 1) << 8,
+            0x4000..0x6000 => match self.rumble {
+                Some(motor) => {
+                    // bit 3 drives the motor instead of selecting RAM
+                    self.ram_bank = b as usize & 0x07;
+                    if motor != bit(b, 3) {
+                        self.rumble = Some(bit(b, 3));
+                        self.events.push(MbcEvent::Rumble(bit(b, 3)));
+                    }
+                }
+                None => self.ram_bank = b as usize & 0x0F,
+            },
+            0x6000..0x8000 => (),
+            0xA000..0xC000 if self.ram_enable => {
+                if let Some(addr) = self.eram_addr(addr) {
+                    self.eram[addr] = b;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn save_data(&self) -> Option<Vec<u8>> {
+        (self.battery && !self.eram.is_empty()).then(|| self.eram.clone())
+    }
+
+    fn load_save(&mut self, data: &[u8]) {
+        let len: usize = data.len().min(self.eram.len());
+        self.eram[..len].copy_from_slice(&data[..len]);
+    }
+
+    fn take_events(&mut self) -> Vec<MbcEvent> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+impl Mbc5 {
+    pub fn new(data: Vec<u8>) -> Self {
+        let (rumble, battery, ram_banks) = match data[0x0147] {
+            0x19 => (false, false, 0),
+            0x1A => (false, false, header_ram(data[0x0149])),
+            0x1B => (false, true, header_ram(data[0x0149])),
+            0x1C => (true, false, 0),
+            0x1D => (true, false, header_ram(data[0x0149])),
+            0x1E => (true, true, header_ram(data[0x0149])),
+            _ => panic!(),
+        };
+        let rom_banks: usize = header_rom(data[0x0148]);
+        Self {
+            rom: data,
+            eram: vec![0; 0x2000 * ram_banks],
+            rom_bank: 1,
+            ram_bank: 0,
+            ram_enable: false,
+            rom_banks,
+            rumble: rumble.then_some(false),
+            battery,
+            events: Vec::new(),
+        }
+    }
+
+    fn eram_addr(&self, addr: u16) -> Option<usize> {
+        let addr: usize = (addr as usize & 0x1FFF) + self.ram_bank * 0x2000;
+        (addr < self.eram.len()).then_some(addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::banked_rom;
+
+    #[test]
+    fn nine_bit_banks() {
+        let mut mbc: Mbc5 = Mbc5::new(banked_rom(0x19, 0x08, 512));
+        mbc.write_byte(0x2000, 0x23);
+        mbc.write_byte(0x3000, 0x01);
+        assert_eq!(mbc.read_word(0x4000), 0x123);
+        mbc.write_byte(0x2000, 0x00);
+        assert_eq!(mbc.read_word(0x4000), 0x100);
+        // unlike MBC1, bank 0 can be mapped at 0x4000
+        mbc.write_byte(0x3000, 0x00);
+        assert_eq!(mbc.read_word(0x4000), 0x000);
+    }
+
+    #[test]
+    fn small_rom_wraps() {
+        let mut mbc: Mbc5 = Mbc5::new(banked_rom(0x19, 0x02, 8));
+        mbc.write_byte(0x2000, 0x0B);
+        mbc.write_byte(0x3000, 0x01);
+        assert_eq!(mbc.read_word(0x4000), 3);
+    }
+
+    #[test]
+    fn ram_banks() {
+        let mut mbc: Mbc5 = Mbc5::new(banked_rom(0x1B, 0x02, 8));
+        assert_eq!(mbc.read_byte(0xA000), 0xFF);
+        mbc.write_byte(0x0000, 0x0A);
+        for bank in 0..4 {
+            mbc.write_byte(0x4000, bank);
+            mbc.write_byte(0xA000, 0x10 | bank);
+        }
+        mbc.write_byte(0x4000, 0x02);
+        assert_eq!(mbc.read_byte(0xA000), 0x12);
+        // banks past the RAM size read open bus
+        mbc.write_byte(0x4000, 0x05);
+        assert_eq!(mbc.read_byte(0xA000), 0xFF);
+        assert_eq!(mbc.save_data().unwrap()[0x6000], 0x13);
+    }
+
+    #[test]
+    fn rumble() {
+        let mut mbc: Mbc5 = Mbc5::new(banked_rom(0x1E, 0x02, 8));
+        mbc.write_byte(0x0000, 0x0A);
+        mbc.write_byte(0x4000, 0x08);
+        mbc.write_byte(0xA000, 0x42);
+        // bit 3 belongs to the motor, so the RAM bank is still 0
+        mbc.write_byte(0x4000, 0x09);
+        mbc.write_byte(0x4000, 0x00);
+        assert_eq!(mbc.read_byte(0xA000), 0x42);
+        assert_eq!(
+            mbc.take_events(),
+            [MbcEvent::Rumble(true), MbcEvent::Rumble(false)]
+        );
+        assert!(mbc.take_events().is_empty());
+    }
+}
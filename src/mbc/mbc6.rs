@@ -0,0 +1,220 @@
+use crate::cpu::combine_u8;
+use crate::mbc::Mbc;
+use crate::utils::*;
+
+const RAM_SIZE: usize = 0x8000;
+const FLASH_SIZE: usize = 0x100000;
+const FLASH_SECTOR: usize = 0x20000;
+
+/// MBC6 splits both ROM and RAM into two independently banked halves, and either ROM
+/// half can map the 1 MiB flash chip instead.
+pub struct Mbc6 {
+    rom: Vec<u8>,
+    eram: Vec<u8>,
+    flash: Vec<u8>,
+    ram_enable: bool,
+    ram_banks: [usize; 2],   // 4 KiB banks at 0xA000 and 0xB000
+    rom_banks: [usize; 2],   // 8 KiB banks at 0x4000 and 0x6000
+    flash_select: [bool; 2], // which of those map flash instead of ROM
+    flash_enable: bool,
+    flash_write: bool,
+    command: FlashCommand,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum FlashCommand {
+    Ready,
+    Unlock1, // 0xAA written to 0x5555
+    Unlock2, // then 0x55 to 0x2AAA
+    Program, // 0xA0: the next write programs one byte
+    Erase,   // 0x80: needs a second unlock
+    EraseUnlock1,
+    EraseUnlock2, // then 0x30 erases a sector or 0x10 the chip
+}
+
+impl Mbc for Mbc6 {
+    fn boot(&self) {}
+
+    fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..0x4000 => self.rom[addr as usize],
+            0x4000..0x8000 => {
+                let half: usize = (addr as usize - 0x4000) / 0x2000;
+                let offset: usize = self.rom_banks[half] * 0x2000 + (addr as usize & 0x1FFF);
+                if self.flash_select[half] {
+                    self.flash[offset % FLASH_SIZE]
+                } else {
+                    self.rom[offset % self.rom.len()]
+                }
+            }
+            0xA000..0xC000 if self.ram_enable => self.eram[self.eram_addr(addr)],
+            _ => 0xFF,
+        }
+    }
+
+    fn read_word(&self, addr: u16) -> u16 {
+        combine_u8(self.read_byte(addr + 1), self.read_byte(addr))
+    }
+
+    fn write_byte(&mut self, addr: u16, b: u8) {
+        match addr {
+            0x0000..0x0400 => self.ram_enable = b & 0x0F == 0x0A,
+            0x0400..0x0800 => self.ram_banks[0] = b as usize & 0x07,
+            0x0800..0x0C00 => self.ram_banks[1] = b as usize & 0x07,
+            0x0C00..0x1000 => self.flash_enable = bit(b, 0),
+            0x1000..0x2000 => self.flash_write = bit(b, 0),
+            0x2000..0x2800 => self.rom_banks[0] = b as usize & 0x7F,
+            0x2800..0x3000 => self.flash_select[0] = b == 0x08,
+            0x3000..0x3800 => self.rom_banks[1] = b as usize & 0x7F,
+            0x3800..0x4000 => self.flash_select[1] = b == 0x08,
+            0x4000..0x8000 => {
+                let half: usize = (addr as usize - 0x4000) / 0x2000;
+                if self.flash_select[half] && self.flash_enable {
+                    let offset: usize =
+                        (self.rom_banks[half] * 0x2000 + (addr as usize & 0x1FFF)) % FLASH_SIZE;
+                    self.flash_command(offset, b);
+                }
+            }
+            0xA000..0xC000 if self.ram_enable => {
+                let addr: usize = self.eram_addr(addr);
+                self.eram[addr] = b;
+            }
+            _ => (),
+        }
+    }
+
+    /// RAM followed by the flash chip.
+    fn save_data(&self) -> Option<Vec<u8>> {
+        Some([&self.eram[..], &self.flash[..]].concat())
+    }
+
+    fn load_save(&mut self, data: &[u8]) {
+        let len: usize = data.len().min(RAM_SIZE);
+        self.eram[..len].copy_from_slice(&data[..len]);
+        let flash: &[u8] = &data[len..];
+        let len: usize = flash.len().min(FLASH_SIZE);
+        self.flash[..len].copy_from_slice(&flash[..len]);
+    }
+}
+
+impl Mbc6 {
+    /// MBC6 (0x20), with 32 KiB of RAM and 1 MiB of flash, both battery-backed.
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            rom: data,
+            eram: vec![0; RAM_SIZE],
+            flash: vec![0xFF; FLASH_SIZE],
+            ram_enable: false,
+            ram_banks: [0, 0],
+            rom_banks: [0, 0],
+            flash_select: [false, false],
+            flash_enable: false,
+            flash_write: false,
+            command: FlashCommand::Ready,
+        }
+    }
+
+    fn eram_addr(&self, addr: u16) -> usize {
+        let half: usize = (addr as usize - 0xA000) / 0x1000;
+        self.ram_banks[half] * 0x1000 + (addr as usize & 0x0FFF)
+    }
+
+    /// The flash takes JEDEC-style command sequences; programming and erasing finish
+    /// immediately, so status polling sees the final data straight away.
+    fn flash_command(&mut self, offset: usize, b: u8) {
+        use FlashCommand::*;
+        let cmd: usize = offset & 0x7FFF;
+        self.command = match (self.command, cmd, b) {
+            (_, _, 0xF0) => Ready,
+            (Ready, 0x5555, 0xAA) => Unlock1,
+            (Unlock1, 0x2AAA, 0x55) => Unlock2,
+            (Unlock2, 0x5555, 0xA0) => Program,
+            (Unlock2, 0x5555, 0x80) => Erase,
+            (Erase, 0x5555, 0xAA) => EraseUnlock1,
+            (EraseUnlock1, 0x2AAA, 0x55) => EraseUnlock2,
+            (Program, _, b) => {
+                // programming can only clear bits
+                if self.flash_write {
+                    self.flash[offset] &= b;
+                }
+                Ready
+            }
+            (EraseUnlock2, _, 0x30) => {
+                if self.flash_write {
+                    let sector: usize = offset / FLASH_SECTOR * FLASH_SECTOR;
+                    self.flash[sector..sector + FLASH_SECTOR].fill(0xFF);
+                }
+                Ready
+            }
+            (EraseUnlock2, 0x5555, 0x10) => {
+                if self.flash_write {
+                    self.flash.fill(0xFF);
+                }
+                Ready
+            }
+            _ => Ready,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::banked_rom;
+
+    /// Sixteen 8 KiB banks, each starting with its number.
+    fn mbc() -> Mbc6 {
+        let mut rom: Vec<u8> = banked_rom(0x20, 0x02, 8);
+        for bank in 0..16 {
+            rom[bank * 0x2000] = bank as u8;
+        }
+        Mbc6::new(rom)
+    }
+
+    #[test]
+    fn rom_halves() {
+        let mut mbc: Mbc6 = mbc();
+        mbc.write_byte(0x2000, 3);
+        mbc.write_byte(0x3000, 5);
+        assert_eq!((mbc.read_byte(0x4000), mbc.read_byte(0x6000)), (3, 5));
+        mbc.write_byte(0x2000, 0x12);
+        assert_eq!(mbc.read_byte(0x4000), 2);
+    }
+
+    #[test]
+    fn ram_halves() {
+        let mut mbc: Mbc6 = mbc();
+        mbc.write_byte(0x0000, 0x0A);
+        mbc.write_byte(0x0400, 1);
+        mbc.write_byte(0x0800, 2);
+        mbc.write_byte(0xA000, 0x11);
+        mbc.write_byte(0xB000, 0x22);
+        mbc.write_byte(0x0400, 2);
+        assert_eq!(mbc.read_byte(0xA000), 0x22);
+        mbc.write_byte(0x0000, 0x00);
+        assert_eq!(mbc.read_byte(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn flash_program() {
+        let mut mbc: Mbc6 = mbc();
+        // flash banks 2 and 1 put 0x5555 and 0x2AAA in reach of both halves
+        mbc.write_byte(0x2000, 2);
+        mbc.write_byte(0x2800, 0x08);
+        mbc.write_byte(0x3000, 1);
+        mbc.write_byte(0x3800, 0x08);
+        mbc.write_byte(0x0C00, 1);
+        mbc.write_byte(0x1000, 1);
+        assert_eq!(mbc.read_byte(0x6000), 0xFF);
+        mbc.write_byte(0x5555, 0xAA);
+        mbc.write_byte(0x6AAA, 0x55);
+        mbc.write_byte(0x5555, 0xA0);
+        mbc.write_byte(0x6000, 0x3C);
+        assert_eq!(mbc.read_byte(0x6000), 0x3C);
+        // without a command sequence the write is ignored
+        mbc.write_byte(0x6001, 0x00);
+        assert_eq!(mbc.read_byte(0x6001), 0xFF);
+        mbc.write_byte(0x3800, 0x00);
+        assert_eq!(mbc.read_byte(0x6000), 1);
+    }
+}
@@ -0,0 +1,343 @@
+use crate::cpu::combine_u8;
+use crate::mbc::{header_rom, Mbc};
+use crate::utils::*;
+
+// accelerometer reading when level, and how far one g moves it
+const ACCEL_CENTRE: f32 = 0x81D0 as f32;
+const ACCEL_G: f32 = 0x70 as f32;
+
+pub struct Mbc7 {
+    rom: Vec<u8>,
+    rom_bank: usize, // 7 BITS
+    rom_banks: usize,
+    ram_enable: (bool, bool), // both 0x0A at 0x0000 and 0x40 at 0x4000 are needed
+    tilt: (f32, f32),
+    latch: (u16, u16),
+    erased: bool, // the latch must be erased with 0x55 before 0xAA samples it again
+    eeprom: Eeprom,
+}
+
+impl Mbc for Mbc7 {
+    fn boot(&self) {}
+
+    fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..0x4000 => self.rom[addr as usize],
+            0x4000..0x8000 => self.rom[(addr as usize & 0x3FFF) + self.rom_bank * 0x4000],
+            0xA000..0xB000 if self.ram_enable == (true, true) => match addr >> 4 & 0x0F {
+                0x2 => self.latch.0 as u8,
+                0x3 => (self.latch.0 >> 8) as u8,
+                0x4 => self.latch.1 as u8,
+                0x5 => (self.latch.1 >> 8) as u8,
+                0x6 => 0x00,
+                0x8 => self.eeprom.read(),
+                _ => 0xFF,
+            },
+            0xA000..0xC000 => 0xFF,
+            _ => 0xFF,
+        }
+    }
+
+    fn read_word(&self, addr: u16) -> u16 {
+        combine_u8(self.read_byte(addr + 1), self.read_byte(addr))
+    }
+
+    fn write_byte(&mut self, addr: u16, b: u8) {
+        match addr {
+            0x0000..0x2000 => self.ram_enable.0 = b == 0x0A,
+            0x2000..0x4000 => self.rom_bank = (b as usize & 0x7F) & (self.rom_banks - 1),
+            0x4000..0x6000 => self.ram_enable.1 = b == 0x40,
+            0x6000..0x8000 => (),
+            0xA000..0xB000 if self.ram_enable == (true, true) => match addr >> 4 & 0x0F {
+                0x0 if b == 0x55 => {
+                    self.latch = (0x8000, 0x8000);
+                    self.erased = true;
+                }
+                0x1 if b == 0xAA && self.erased => {
+                    let axis = |g: f32| (ACCEL_CENTRE + ACCEL_G * g).clamp(0.0, 65535.0) as u16;
+                    self.latch = (axis(self.tilt.0), axis(self.tilt.1));
+                    self.erased = false;
+                }
+                0x8 => self.eeprom.write(b),
+                _ => (),
+            },
+            0xA000..0xC000 => (),
+            _ => (),
+        }
+    }
+
+    fn save_data(&self) -> Option<Vec<u8>> {
+        Some(self.eeprom.data.to_vec())
+    }
+
+    fn load_save(&mut self, data: &[u8]) {
+        let len: usize = data.len().min(self.eeprom.data.len());
+        self.eeprom.data[..len].copy_from_slice(&data[..len]);
+    }
+
+    fn set_tilt(&mut self, x: f32, y: f32) {
+        self.tilt = (x, y);
+    }
+}
+
+impl Mbc7 {
+    /// MBC7+SENSOR+RUMBLE+RAM+BATTERY (0x22). The rumble was never used.
+    pub fn new(data: Vec<u8>) -> Self {
+        let rom_banks: usize = header_rom(data[0x0148]);
+        Self {
+            rom: data,
+            rom_bank: 1,
+            rom_banks,
+            ram_enable: (false, false),
+            tilt: (0.0, 0.0),
+            latch: (0x8000, 0x8000),
+            erased: false,
+            eeprom: Eeprom::new(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum EepromState {
+    Idle,
+    Command,
+    Read { addr: u8, bits: u8 },
+    Write { addr: Option<u8> }, // None writes every word
+}
+
+/// 93LC56 serial EEPROM in 16-bit mode: 128 words, clocked one bit at a time through
+/// CS (bit 7), CLK (bit 6), DI (bit 1) and DO (bit 0).
+struct Eeprom {
+    data: [u8; 256], // little-endian words
+    cs: bool,
+    clk: bool,
+    di: bool,
+    dout: bool,
+    write_enable: bool,
+    shift: u16,
+    count: u8,
+    state: EepromState,
+}
+
+impl Eeprom {
+    fn new() -> Self {
+        Self {
+            data: [0xFF; 256],
+            cs: false,
+            clk: false,
+            di: false,
+            dout: true,
+            write_enable: false,
+            shift: 0,
+            count: 0,
+            state: EepromState::Idle,
+        }
+    }
+
+    fn read(&self) -> u8 {
+        (self.cs as u8) << 7 | (self.clk as u8) << 6 | (self.di as u8) << 1 | self.dout as u8
+    }
+
+    fn write(&mut self, b: u8) {
+        let rising: bool = !self.clk && bit(b, 6);
+        self.cs = bit(b, 7);
+        self.clk = bit(b, 6);
+        self.di = bit(b, 1);
+        if !self.cs {
+            self.state = EepromState::Idle;
+            return;
+        }
+        if rising {
+            self.clock();
+        }
+    }
+
+    fn clock(&mut self) {
+        match self.state {
+            EepromState::Idle => {
+                // commands begin with a start bit
+                if self.di {
+                    self.begin(EepromState::Command);
+                }
+            }
+            EepromState::Command => {
+                if self.shift_in() == 10 {
+                    self.command();
+                }
+            }
+            EepromState::Read { addr, bits } => {
+                let word: u16 = self.word(addr);
+                self.dout = bit(word, bits - 1);
+                // sequential reads roll on into the next word
+                self.state = match bits {
+                    1 => EepromState::Read {
+                        addr: (addr + 1) & 0x7F,
+                        bits: 16,
+                    },
+                    _ => EepromState::Read {
+                        addr,
+                        bits: bits - 1,
+                    },
+                };
+            }
+            EepromState::Write { addr } => {
+                if self.shift_in() == 16 {
+                    if self.write_enable {
+                        match addr {
+                            Some(addr) => self.set_word(addr, self.shift),
+                            None => (0..0x80).for_each(|addr| self.set_word(addr, self.shift)),
+                        }
+                    }
+                    // writes complete instantly, so DO reports ready straight away
+                    self.dout = true;
+                    self.state = EepromState::Idle;
+                }
+            }
+        }
+    }
+
+    /// Decodes the 2-bit opcode and 8 address bits after the start bit.
+    fn command(&mut self) {
+        let addr: u8 = self.shift as u8 & 0x7F;
+        self.state = EepromState::Idle;
+        match (self.shift >> 8 & 3, self.shift >> 6 & 3) {
+            // READ, preceded by a dummy zero
+            (0b10, _) => {
+                self.dout = false;
+                self.state = EepromState::Read { addr, bits: 16 };
+            }
+            (0b01, _) => self.begin(EepromState::Write { addr: Some(addr) }),
+            // ERASE
+            (0b11, _) => {
+                if self.write_enable {
+                    self.set_word(addr, 0xFFFF);
+                }
+                self.dout = true;
+            }
+            // EWDS
+            (0b00, 0b00) => self.write_enable = false,
+            // WRAL
+            (0b00, 0b01) => self.begin(EepromState::Write { addr: None }),
+            // ERAL
+            (0b00, 0b10) => {
+                if self.write_enable {
+                    self.data.fill(0xFF);
+                }
+                self.dout = true;
+            }
+            // EWEN
+            (0b00, 0b11) => self.write_enable = true,
+            _ => unreachable!(),
+        }
+    }
+
+    fn begin(&mut self, state: EepromState) {
+        self.shift = 0;
+        self.count = 0;
+        self.state = state;
+    }
+
+    fn shift_in(&mut self) -> u8 {
+        self.shift = self.shift << 1 | self.di as u16;
+        self.count += 1;
+        self.count
+    }
+
+    fn word(&self, addr: u8) -> u16 {
+        combine_u8(
+            self.data[addr as usize * 2 + 1],
+            self.data[addr as usize * 2],
+        )
+    }
+
+    fn set_word(&mut self, addr: u8, word: u16) {
+        self.data[addr as usize * 2..addr as usize * 2 + 2].copy_from_slice(&word.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::banked_rom;
+
+    fn mbc() -> Mbc7 {
+        let mut mbc: Mbc7 = Mbc7::new(banked_rom(0x22, 0x02, 8));
+        mbc.write_byte(0x0000, 0x0A);
+        mbc.write_byte(0x4000, 0x40);
+        mbc
+    }
+
+    /// Clocks bits into the EEPROM MSB first with CS held high.
+    fn send(mbc: &mut Mbc7, value: u32, bits: u8) {
+        for i in (0..bits).rev() {
+            let di: u8 = ((value >> i & 1) as u8) << 1;
+            mbc.write_byte(0xA080, 0x80 | di);
+            mbc.write_byte(0xA080, 0xC0 | di);
+        }
+    }
+
+    fn deselect(mbc: &mut Mbc7) {
+        mbc.write_byte(0xA080, 0x00);
+    }
+
+    fn read_word(mbc: &mut Mbc7, addr: u32) -> u16 {
+        send(mbc, 0b110 << 8 | addr, 11);
+        assert_eq!(mbc.read_byte(0xA080) & 1, 0);
+        let mut word: u16 = 0;
+        for _ in 0..16 {
+            send(mbc, 0, 1);
+            word = word << 1 | (mbc.read_byte(0xA080) & 1) as u16;
+        }
+        deselect(mbc);
+        word
+    }
+
+    #[test]
+    fn ram_enable() {
+        let mut mbc: Mbc7 = mbc();
+        assert_eq!(mbc.read_byte(0xA020), 0x00);
+        mbc.write_byte(0x4000, 0x00);
+        assert_eq!(mbc.read_byte(0xA020), 0xFF);
+        mbc.write_byte(0x2000, 0x05);
+        assert_eq!(mbc.read_byte(0x4000), 5);
+    }
+
+    #[test]
+    fn accelerometer() {
+        let mut mbc: Mbc7 = mbc();
+        mbc.set_tilt(1.0, -0.5);
+        // sampling needs an erase first
+        mbc.write_byte(0xA010, 0xAA);
+        assert_eq!(mbc.read_byte(0xA020), 0x00);
+        mbc.write_byte(0xA000, 0x55);
+        mbc.write_byte(0xA010, 0xAA);
+        let x: u16 = combine_u8(mbc.read_byte(0xA030), mbc.read_byte(0xA020));
+        let y: u16 = combine_u8(mbc.read_byte(0xA050), mbc.read_byte(0xA040));
+        assert_eq!((x, y), (0x81D0 + 0x70, 0x81D0 - 0x38));
+        mbc.set_tilt(0.0, 0.0);
+        mbc.write_byte(0xA010, 0xAA);
+        assert_eq!(mbc.read_byte(0xA020), 0x40);
+    }
+
+    #[test]
+    fn eeprom_write_and_read() {
+        let mut mbc: Mbc7 = mbc();
+        // writes are ignored until EWEN
+        send(&mut mbc, 0b101 << 8 | 0x12, 11);
+        send(&mut mbc, 0xBEEF, 16);
+        deselect(&mut mbc);
+        assert_eq!(read_word(&mut mbc, 0x12), 0xFFFF);
+        send(&mut mbc, 0b100 << 8 | 0xC0, 11);
+        deselect(&mut mbc);
+        send(&mut mbc, 0b101 << 8 | 0x12, 11);
+        send(&mut mbc, 0xBEEF, 16);
+        assert_eq!(mbc.read_byte(0xA080) & 1, 1);
+        deselect(&mut mbc);
+        assert_eq!(read_word(&mut mbc, 0x12), 0xBEEF);
+        assert_eq!(mbc.save_data().unwrap()[0x24..0x26], [0xEF, 0xBE]);
+        // ERASE sets the word back to all ones
+        send(&mut mbc, 0b111 << 8 | 0x12, 11);
+        deselect(&mut mbc);
+        assert_eq!(read_word(&mut mbc, 0x12), 0xFFFF);
+    }
+}
@@ -0,0 +1,240 @@
+use crate::cpu::combine_u8;
+use crate::mbc::header::has_logo;
+use crate::mbc::{header_ram, Mbc};
+use crate::utils::*;
+
+/// MMM01 multicarts keep the menu's header in the last 32 KiB of the ROM, which is
+/// what the cartridge maps at power on.
+pub fn detect(rom: &[u8]) -> bool {
+    let Some(base) = rom.len().checked_sub(0x8000) else {
+        return false;
+    };
+    has_logo(rom, base) && matches!(rom[base + 0x0147], 0x0B..=0x0D)
+}
+
+pub struct Mmm01 {
+    rom: Vec<u8>,
+    eram: Vec<u8>,
+    mapped: bool, // until the menu sets this, most bits below can still be written
+    ram_enable: bool,
+    rom_bank: usize, // 9 BITS: RB0-4 from 0x2000, RB5-6 from 0x2000, RB7-8 from 0x4000
+    ram_bank: usize, // 4 BITS: RA0-1 from 0x4000, RA2-3 from 0x4000
+    rom_mask: usize, // RB1-4 that the menu fixed for the selected game
+    ram_mask: usize, // RA0-1 that the menu fixed
+    mode: bool,
+    mode_lock: bool,
+    multiplex: bool, // MBC1 mode swaps RB5-6 for RA0-1
+    battery: bool,
+}
+
+impl Mbc for Mmm01 {
+    fn boot(&self) {}
+
+    fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..0x8000 if !self.mapped => self.rom[self.rom.len() - 0x8000 + addr as usize],
+            0x0000..0x4000 => self.rom[self.rom_addr(self.lower_bank(), addr)],
+            0x4000..0x8000 => self.rom[self.rom_addr(self.upper_bank(), addr)],
+            0xA000..0xC000 => self
+                .eram_addr(addr)
+                .filter(|_| self.ram_enable)
+                .map_or(0xFF, |a| self.eram[a]),
+            _ => 0xFF,
+        }
+    }
+
+    fn read_word(&self, addr: u16) -> u16 {
+        combine_u8(self.read_byte(addr + 1), self.read_byte(addr))
+    }
+
+    fn write_byte(&mut self, addr: u16, b: u8) {
+        let b: usize = b as usize;
+        match addr {
+            0x0000..0x2000 => {
+                self.ram_enable = b & 0x0F == 0x0A;
+                if !self.mapped {
+                    self.ram_mask = b >> 4 & 0x03;
+                    self.mapped = bit(b as u8, 6);
+                }
+            }
+            0x2000..0x4000 => {
+                // bits fixed by the mask keep the menu's choice
+                let fixed: usize = self.rom_mask << 1;
+                let low: usize = (self.rom_bank & fixed) | (b & 0x1F & !fixed);
+                self.rom_bank = (self.rom_bank & !0x1F) | low;
+                if !self.mapped {
+                    self.rom_bank = (self.rom_bank & !0x60) | (b & 0x60);
+                }
+            }
+            0x4000..0x6000 => {
+                let low: usize = (self.ram_bank & self.ram_mask) | (b & 0x03 & !self.ram_mask);
+                self.ram_bank = (self.ram_bank & !0x03) | low;
+                if !self.mapped {
+                    self.ram_bank = (self.ram_bank & 0x03) | (b & 0x0C);
+                    self.rom_bank = (self.rom_bank & 0x7F) | (b >> 4 & 0x03) << 7;
+                    self.mode_lock = bit(b as u8, 6);
+                }
+            }
+            0x6000..0x8000 => {
+                if !self.mode_lock {
+                    self.mode = bit(b as u8, 0);
+                }
+                if !self.mapped {
+                    self.rom_mask = b >> 2 & 0x0F;
+                    self.multiplex = bit(b as u8, 6);
+                }
+            }
+            0xA000..0xC000 if self.ram_enable => {
+                if let Some(addr) = self.eram_addr(addr) {
+                    self.eram[addr] = b as u8;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn save_data(&self) -> Option<Vec<u8>> {
+        (self.battery && !self.eram.is_empty()).then(|| self.eram.clone())
+    }
+
+    fn load_save(&mut self, data: &[u8]) {
+        let len: usize = data.len().min(self.eram.len());
+        self.eram[..len].copy_from_slice(&data[..len]);
+    }
+}
+
+impl Mmm01 {
+    pub fn new(data: Vec<u8>) -> Self {
+        let header: usize = data.len() - 0x8000;
+        let (battery, ram_banks) = match data[header + 0x0147] {
+            0x0B => (false, 0),
+            0x0C => (false, header_ram(data[header + 0x0149])),
+            0x0D => (true, header_ram(data[header + 0x0149])),
+            _ => panic!(),
+        };
+        Self {
+            rom: data,
+            eram: vec![0; 0x2000 * ram_banks],
+            mapped: false,
+            ram_enable: false,
+            rom_bank: 0,
+            ram_bank: 0,
+            rom_mask: 0,
+            ram_mask: 0,
+            mode: false,
+            mode_lock: false,
+            multiplex: false,
+            battery,
+        }
+    }
+
+    /// RB5-6, which MBC1 mode hands over to RA0-1 when multiplexed.
+    fn rom_mid(&self) -> usize {
+        if self.multiplex && self.mode {
+            0
+        } else {
+            self.rom_bank & 0x60
+        }
+    }
+
+    fn lower_bank(&self) -> usize {
+        let fixed: usize = self.rom_mask << 1;
+        (self.rom_bank & 0x180) | self.rom_mid() | (self.rom_bank & fixed)
+    }
+
+    fn upper_bank(&self) -> usize {
+        let fixed: usize = self.rom_mask << 1;
+        let mut low: usize = self.rom_bank & 0x1F;
+        // like MBC1, zero in the writable bits selects one
+        if low & !fixed == 0 {
+            low |= 1;
+        }
+        (self.rom_bank & 0x180) | self.rom_mid() | low
+    }
+
+    fn rom_addr(&self, bank: usize, addr: u16) -> usize {
+        ((addr as usize & 0x3FFF) + bank * 0x4000) % self.rom.len()
+    }
+
+    fn eram_addr(&self, addr: u16) -> Option<usize> {
+        let bank: usize = if self.multiplex && self.mode {
+            (self.ram_bank & 0x0C) | (self.rom_bank >> 5 & 0x03)
+        } else {
+            self.ram_bank
+        };
+        let addr: usize = (addr as usize & 0x1FFF) + bank * 0x2000;
+        (addr < self.eram.len()).then_some(addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::header::{header_checksum, NINTENDO_LOGO};
+    use crate::mbc::{banked_rom, from_vec, LoadError};
+
+    /// Eight banks numbered at 0x0000 and 0x2000, with the menu header in the last 32 KiB.
+    fn rom() -> Vec<u8> {
+        let mut rom: Vec<u8> = banked_rom(0x0D, 0x02, 8);
+        for bank in 0..8 {
+            rom[bank * 0x4000 + 0x2000] = bank as u8;
+        }
+        let base: usize = rom.len() - 0x8000;
+        let header: &mut [u8] = &mut rom[base..];
+        header[0x0104..0x0134].copy_from_slice(&NINTENDO_LOGO);
+        header[0x0147] = 0x0D;
+        header[0x0148] = 0x02;
+        header[0x0149] = 0x03;
+        header[0x014D] = header_checksum(header);
+        rom
+    }
+
+    #[test]
+    fn menu_then_game() {
+        let mut mbc: Mmm01 = Mmm01::new(rom());
+        assert_eq!((mbc.read_byte(0x0000), mbc.read_byte(0x4000)), (6, 7));
+        // the menu fixes RB1-4 to a 32 KiB game at banks 4 and 5, then maps it
+        mbc.write_byte(0x2000, 0x04);
+        mbc.write_byte(0x6000, 0x3C);
+        mbc.write_byte(0x0000, 0x40);
+        assert_eq!((mbc.read_byte(0x0000), mbc.read_byte(0x4000)), (4, 5));
+        // the game can no longer leave its banks or change the mask
+        mbc.write_byte(0x2000, 0x00);
+        mbc.write_byte(0x6000, 0x00);
+        assert_eq!((mbc.read_byte(0x0000), mbc.read_byte(0x4000)), (4, 5));
+        mbc.write_byte(0x2000, 0x03);
+        assert_eq!(mbc.read_byte(0x4000), 5);
+    }
+
+    #[test]
+    fn ram_banks() {
+        let mut mbc: Mmm01 = Mmm01::new(rom());
+        mbc.write_byte(0x0000, 0x4A);
+        for bank in 0..4 {
+            mbc.write_byte(0x4000, bank);
+            mbc.write_byte(0xA000, 0x10 | bank);
+        }
+        mbc.write_byte(0x4000, 2);
+        assert_eq!(mbc.read_byte(0xA000), 0x12);
+        mbc.write_byte(0x0000, 0x00);
+        assert_eq!(mbc.read_byte(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn header_checked() {
+        let rom: Vec<u8> = rom();
+        assert!(detect(&rom));
+        // the loader hands back the menu's header, not the first game's
+        let (_, header) = from_vec(rom.clone()).unwrap();
+        assert_eq!(header.cartridge_type, 0x0D);
+        let mut bad: Vec<u8> = rom.clone();
+        bad[rom.len() - 0x8000 + 0x014D] ^= 0xFF;
+        assert!(matches!(from_vec(bad), Err(LoadError::BadHeader(_))));
+        // the menu header still claims eight banks
+        let small: Vec<u8> = rom[0x4000..].to_vec();
+        assert!(matches!(
+            from_vec(small),
+            Err(LoadError::TruncatedRom { .. })
+        ));
+    }
+}
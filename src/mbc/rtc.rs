@@ -0,0 +1,94 @@
+use crate::CLOCK_SPEED;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where a cartridge real-time clock takes its time from.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum ClockSource {
+    #[default]
+    /// Follows the host clock, including time that passed while the emulator was closed.
+    Wall,
+    /// Advances only with emulated cycles, so runs are deterministic.
+    Cycles,
+}
+
+#[derive(Clone)]
+pub struct RtcClock {
+    source: ClockSource,
+    cycles: u64,
+    last: u64,
+}
+
+impl RtcClock {
+    pub fn new(source: ClockSource) -> Self {
+        Self {
+            source,
+            cycles: 0,
+            last: unix_now(),
+        }
+    }
+
+    pub fn cycle(&mut self, cycles: u16) {
+        if self.source == ClockSource::Cycles {
+            self.cycles += cycles as u64;
+        }
+    }
+
+    /// Whole seconds elapsed since the last call.
+    pub fn elapsed(&mut self) -> u64 {
+        match self.source {
+            ClockSource::Wall => {
+                let now: u64 = unix_now();
+                let secs: u64 = now.saturating_sub(self.last);
+                self.last += secs;
+                secs
+            }
+            ClockSource::Cycles => {
+                let secs: u64 = self.cycles / CLOCK_SPEED as u64;
+                self.cycles %= CLOCK_SPEED as u64;
+                secs
+            }
+        }
+    }
+
+    /// Restarts the current second, as writing the seconds register does.
+    pub fn reset_subsecond(&mut self) {
+        match self.source {
+            ClockSource::Wall => self.last = unix_now(),
+            ClockSource::Cycles => self.cycles = 0,
+        }
+    }
+
+    /// A copy of a cartridge's clock state brought up to date by `update`, for save file
+    /// trailers. The running clock is left alone, so saving doesn't eat into its seconds.
+    pub fn snapshot<T: Clone>(rtc: &T, update: impl FnOnce(&mut T)) -> T {
+        let mut rtc: T = rtc.clone();
+        update(&mut rtc);
+        rtc
+    }
+
+    /// Unix time the clock was last brought up to date, for save file trailers.
+    pub fn timestamp(&self) -> u64 {
+        match self.source {
+            ClockSource::Wall => self.last,
+            ClockSource::Cycles => unix_now(),
+        }
+    }
+
+    /// Seconds that passed on the host since a save was written, if following the host clock.
+    pub fn since(&mut self, timestamp: u64) -> u64 {
+        match self.source {
+            ClockSource::Wall => {
+                self.last = unix_now();
+                self.last.saturating_sub(timestamp)
+            }
+            ClockSource::Cycles => 0,
+        }
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
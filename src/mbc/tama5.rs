@@ -0,0 +1,390 @@
+use crate::cpu::combine_u8;
+use crate::mbc::rtc::{ClockSource, RtcClock};
+use crate::mbc::{header_rom, Mbc};
+
+const RAM_SIZE: usize = 0x20;
+const RTC_TRAILER: usize = 15;
+
+/// TAMA5 is driven entirely through nibble registers: 0xA001 selects a register and
+/// 0xA000 reads or writes it. Writing the low address nibble (register 7) runs the
+/// command set up in register 6.
+pub struct Tama5 {
+    rom: Vec<u8>,
+    eram: [u8; RAM_SIZE],
+    rom_banks: usize,
+    rom_bank: usize, // 5 BITS
+    select: u8,
+    unlocked: bool, // set by selecting register 0xA, reads 0xF1 once ready
+    data: u8,       // byte to write, from registers 4 and 5
+    command: u8,    // register 6, address bit 4 and the operation
+    result: u8,     // read back through registers 0xC and 0xD
+    rtc: Rtc,
+}
+
+impl Mbc for Tama5 {
+    fn boot(&self) {}
+
+    fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..0x4000 => self.rom[addr as usize],
+            0x4000..0x8000 => {
+                let bank: usize = self.rom_bank & (self.rom_banks - 1);
+                self.rom[(addr as usize & 0x3FFF) + bank * 0x4000]
+            }
+            0xA000 if self.unlocked => match self.select {
+                0x0A => 0xF1,
+                0x0C => 0xF0 | self.result & 0x0F,
+                0x0D => 0xF0 | self.result >> 4,
+                _ => 0xFF,
+            },
+            0xA000..0xC000 => 0xFF,
+            _ => 0xFF,
+        }
+    }
+
+    fn read_word(&self, addr: u16) -> u16 {
+        combine_u8(self.read_byte(addr + 1), self.read_byte(addr))
+    }
+
+    fn write_byte(&mut self, addr: u16, b: u8) {
+        let nibble: u8 = b & 0x0F;
+        match addr {
+            0x0000..0x8000 => (),
+            0xA001 => {
+                self.select = nibble;
+                if nibble == 0x0A {
+                    self.unlocked = true;
+                }
+            }
+            0xA000 => match self.select {
+                0x0 => self.rom_bank = (self.rom_bank & 0x10) | nibble as usize,
+                0x1 => self.rom_bank = (self.rom_bank & 0x0F) | (nibble as usize & 1) << 4,
+                0x4 => self.data = (self.data & 0xF0) | nibble,
+                0x5 => self.data = (self.data & 0x0F) | nibble << 4,
+                0x6 => self.command = nibble,
+                0x7 => self.run((self.command & 1) << 4 | nibble),
+                _ => (),
+            },
+            0xA002..0xC000 => (),
+            _ => (),
+        }
+    }
+
+    fn cycle(&mut self, cycles: u16) {
+        self.rtc.clock.cycle(cycles);
+    }
+
+    fn save_data(&self) -> Option<Vec<u8>> {
+        let mut data: Vec<u8> = self.eram.to_vec();
+        data.extend_from_slice(&self.rtc.trailer());
+        Some(data)
+    }
+
+    fn load_save(&mut self, data: &[u8]) {
+        let len: usize = data.len().min(RAM_SIZE);
+        self.eram[..len].copy_from_slice(&data[..len]);
+        if data.len() >= RAM_SIZE + RTC_TRAILER {
+            self.rtc
+                .load_trailer(&data[RAM_SIZE..RAM_SIZE + RTC_TRAILER]);
+        }
+    }
+}
+
+impl Tama5 {
+    /// TAMA5 (0xFD), with 32 bytes of battery-backed RAM and a calendar clock.
+    pub fn new(data: Vec<u8>, source: ClockSource) -> Self {
+        let rom_banks: usize = header_rom(data[0x0148]);
+        Self {
+            rom: data,
+            eram: [0; RAM_SIZE],
+            rom_banks,
+            rom_bank: 0,
+            select: 0,
+            unlocked: false,
+            data: 0,
+            command: 0,
+            result: 0,
+            rtc: Rtc::new(source),
+        }
+    }
+
+    /// Operations from bits 1-3 of register 6: 0 writes RAM, 1 reads RAM,
+    /// 2 writes a clock register and 3 reads one.
+    fn run(&mut self, addr: u8) {
+        match self.command >> 1 {
+            0 => self.eram[addr as usize] = self.data,
+            1 => self.result = self.eram[addr as usize],
+            2 => self.rtc.write(addr & 0x0F, self.data & 0x0F),
+            3 => self.result = self.rtc.read(addr & 0x0F),
+            _ => (),
+        }
+    }
+}
+
+/// The TC8521-style calendar clock, read and written as BCD nibbles:
+/// seconds, minutes and hours (units, tens), weekday, day, month and year (units, tens).
+#[derive(Clone)]
+struct Rtc {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    weekday: u8,
+    day: u8,   // 1-31
+    month: u8, // 1-12
+    year: u8,  // 0-99, leap years every 4
+    clock: RtcClock,
+}
+
+impl Rtc {
+    fn new(source: ClockSource) -> Self {
+        Self {
+            second: 0,
+            minute: 0,
+            hour: 0,
+            weekday: 0,
+            day: 1,
+            month: 1,
+            year: 0,
+            clock: RtcClock::new(source),
+        }
+    }
+
+    fn read(&mut self, reg: u8) -> u8 {
+        let secs: u64 = self.clock.elapsed();
+        self.advance(secs);
+        match reg {
+            0x0 => self.second % 10,
+            0x1 => self.second / 10,
+            0x2 => self.minute % 10,
+            0x3 => self.minute / 10,
+            0x4 => self.hour % 10,
+            0x5 => self.hour / 10,
+            0x6 => self.weekday,
+            0x7 => self.day % 10,
+            0x8 => self.day / 10,
+            0x9 => self.month % 10,
+            0xA => self.month / 10,
+            0xB => self.year % 10,
+            0xC => self.year / 10,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, reg: u8, nibble: u8) {
+        let secs: u64 = self.clock.elapsed();
+        self.advance(secs);
+        let tens = |v: u8, n: u8| v % 10 + n * 10;
+        let units = |v: u8, n: u8| v / 10 * 10 + n.min(9);
+        match reg {
+            0x0 => {
+                self.second = units(self.second, nibble).min(59);
+                self.clock.reset_subsecond();
+            }
+            0x1 => self.second = tens(self.second, nibble.min(5)),
+            0x2 => self.minute = units(self.minute, nibble).min(59),
+            0x3 => self.minute = tens(self.minute, nibble.min(5)),
+            0x4 => self.hour = units(self.hour, nibble).min(23),
+            0x5 => self.hour = tens(self.hour, nibble.min(2)).min(23),
+            0x6 => self.weekday = nibble % 7,
+            0x7 => self.day = units(self.day, nibble).clamp(1, 31),
+            0x8 => self.day = tens(self.day, nibble.min(3)).clamp(1, 31),
+            0x9 => self.month = units(self.month, nibble).clamp(1, 12),
+            0xA => self.month = tens(self.month, nibble.min(1)).clamp(1, 12),
+            0xB => self.year = units(self.year, nibble),
+            0xC => self.year = tens(self.year, nibble.min(9)),
+            _ => (),
+        }
+    }
+
+    fn days_in_month(&self) -> u8 {
+        match self.month {
+            2 if self.year.is_multiple_of(4) => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        }
+    }
+
+    fn advance(&mut self, secs: u64) {
+        let total: u64 =
+            self.second as u64 + self.minute as u64 * 60 + self.hour as u64 * 3600 + secs;
+        self.second = (total % 60) as u8;
+        self.minute = (total / 60 % 60) as u8;
+        self.hour = (total / 3600 % 24) as u8;
+        for _ in 0..total / 86400 {
+            self.weekday = (self.weekday + 1) % 7;
+            self.day += 1;
+            if self.day > self.days_in_month() {
+                self.day = 1;
+                self.month += 1;
+                if self.month > 12 {
+                    self.month = 1;
+                    self.year = (self.year + 1) % 100;
+                }
+            }
+        }
+    }
+
+    /// The seven clock fields, then the unix time they were saved at as a little-endian u64.
+    fn trailer(&self) -> [u8; RTC_TRAILER] {
+        let rtc: Rtc = RtcClock::snapshot(self, |rtc: &mut Rtc| {
+            let secs: u64 = rtc.clock.elapsed();
+            rtc.advance(secs);
+        });
+        let mut trailer: [u8; RTC_TRAILER] = [0; RTC_TRAILER];
+        trailer[..7].copy_from_slice(&[
+            rtc.second,
+            rtc.minute,
+            rtc.hour,
+            rtc.weekday,
+            rtc.day,
+            rtc.month,
+            rtc.year,
+        ]);
+        trailer[7..].copy_from_slice(&rtc.clock.timestamp().to_le_bytes());
+        trailer
+    }
+
+    fn load_trailer(&mut self, trailer: &[u8]) {
+        self.second = trailer[0].min(59);
+        self.minute = trailer[1].min(59);
+        self.hour = trailer[2].min(23);
+        self.weekday = trailer[3] % 7;
+        self.day = trailer[4].clamp(1, 31);
+        self.month = trailer[5].clamp(1, 12);
+        self.year = trailer[6] % 100;
+        let timestamp: u64 = u64::from_le_bytes(trailer[7..15].try_into().unwrap());
+        let secs: u64 = self.clock.since(timestamp);
+        self.advance(secs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::banked_rom;
+    use crate::CLOCK_SPEED;
+
+    fn mbc() -> Tama5 {
+        let mut mbc: Tama5 = Tama5::new(banked_rom(0xFD, 0x02, 8), ClockSource::Cycles);
+        mbc.write_byte(0xA001, 0x0A);
+        mbc
+    }
+
+    fn write_reg(mbc: &mut Tama5, reg: u8, nibble: u8) {
+        mbc.write_byte(0xA001, reg);
+        mbc.write_byte(0xA000, nibble);
+    }
+
+    fn write_rtc(mbc: &mut Tama5, reg: u8, nibble: u8) {
+        write_reg(mbc, 0x4, nibble);
+        write_reg(mbc, 0x6, 0x4);
+        write_reg(mbc, 0x7, reg);
+    }
+
+    fn read_rtc(mbc: &mut Tama5, reg: u8) -> u8 {
+        write_reg(mbc, 0x6, 0x6);
+        write_reg(mbc, 0x7, reg);
+        mbc.write_byte(0xA001, 0x0C);
+        mbc.read_byte(0xA000) & 0x0F
+    }
+
+    /// Reads every clock register, seconds first.
+    fn read_clock(mbc: &mut Tama5) -> Vec<u8> {
+        (0x0..=0xC).map(|reg| read_rtc(mbc, reg)).collect()
+    }
+
+    fn run_secs(mbc: &mut Tama5, secs: u32) {
+        for _ in 0..secs * CLOCK_SPEED / 0x8000 {
+            mbc.cycle(0x8000);
+        }
+    }
+
+    /// 23:59:58 on 28/02 in year 3, which isn't a leap year.
+    fn set_clock(mbc: &mut Tama5) {
+        for (reg, nibble) in [8, 5, 9, 5, 3, 2, 0, 8, 2, 2, 0, 3, 0]
+            .into_iter()
+            .enumerate()
+        {
+            write_rtc(mbc, reg as u8, nibble);
+        }
+    }
+
+    #[test]
+    fn rom_bank() {
+        let mut mbc: Tama5 = mbc();
+        write_reg(&mut mbc, 0x0, 5);
+        assert_eq!(mbc.read_byte(0x4000), 5);
+        // bank 0x13 wraps to 3 on an eight bank ROM
+        write_reg(&mut mbc, 0x0, 3);
+        write_reg(&mut mbc, 0x1, 1);
+        assert_eq!(mbc.read_byte(0x4000), 3);
+    }
+
+    #[test]
+    fn ram_through_registers() {
+        let mut mbc: Tama5 = mbc();
+        assert_eq!(mbc.read_byte(0xA000), 0xF1);
+        write_reg(&mut mbc, 0x4, 0xA);
+        write_reg(&mut mbc, 0x5, 0x5);
+        write_reg(&mut mbc, 0x6, 0x1); // write, address bit 4 set
+        write_reg(&mut mbc, 0x7, 0x3);
+        write_reg(&mut mbc, 0x6, 0x3); // read
+        write_reg(&mut mbc, 0x7, 0x3);
+        mbc.write_byte(0xA001, 0x0C);
+        assert_eq!(mbc.read_byte(0xA000), 0xFA);
+        mbc.write_byte(0xA001, 0x0D);
+        assert_eq!(mbc.read_byte(0xA000), 0xF5);
+        assert_eq!(mbc.save_data().unwrap()[0x13], 0x5A);
+    }
+
+    #[test]
+    fn clock_registers() {
+        let mut mbc: Tama5 = mbc();
+        set_clock(&mut mbc);
+        assert_eq!(
+            read_clock(&mut mbc),
+            [8, 5, 9, 5, 3, 2, 0, 8, 2, 2, 0, 3, 0]
+        );
+        // out of range digits are clamped
+        write_rtc(&mut mbc, 0x3, 0x9);
+        assert_eq!(read_rtc(&mut mbc, 0x3), 5);
+    }
+
+    #[test]
+    fn clock_runs() {
+        let mut mbc: Tama5 = mbc();
+        set_clock(&mut mbc);
+        run_secs(&mut mbc, 1);
+        assert_eq!(read_rtc(&mut mbc, 0x0), 9);
+        // midnight rolls the weekday and skips to 01/03
+        run_secs(&mut mbc, 2);
+        assert_eq!(
+            read_clock(&mut mbc),
+            [1, 0, 0, 0, 0, 0, 1, 1, 0, 3, 0, 3, 0]
+        );
+    }
+
+    #[test]
+    fn leap_year() {
+        let mut mbc: Tama5 = mbc();
+        set_clock(&mut mbc);
+        write_rtc(&mut mbc, 0xB, 4);
+        run_secs(&mut mbc, 2);
+        assert_eq!(read_clock(&mut mbc)[7..11], [9, 2, 2, 0]);
+    }
+
+    #[test]
+    fn clock_persists() {
+        let mut running: Tama5 = mbc();
+        set_clock(&mut running);
+        // seconds not yet read out still make it into the save
+        run_secs(&mut running, 5);
+        let save: Vec<u8> = running.save_data().unwrap();
+        assert_eq!(save.len(), RAM_SIZE + RTC_TRAILER);
+        let mut loaded: Tama5 = mbc();
+        loaded.load_save(&save);
+        assert_eq!(read_clock(&mut loaded), read_clock(&mut running));
+        assert_eq!(read_clock(&mut loaded)[..6], [3, 0, 0, 0, 0, 0]);
+    }
+}
@@ -1,48 +1,130 @@
+use crate::apu::Apu;
 use crate::cpu::{combine_u8, split_u16};
-use crate::mbc::{make_mbc, Mbc};
+use crate::joypad::Joypad;
+use crate::mbc::{Mbc, MbcEvent};
 use crate::ppu::Ppu;
+use crate::sgb::Sgb;
 use crate::utils::*;
 
 pub struct Mmu {
     cart: Box<dyn Mbc + 'static>,
     pub ppu: Ppu,
-    wram: Vec<u8>,
+    pub joypad: Joypad,
+    pub apu: Apu,
+    pub sgb: Option<Sgb>,
+    wram: Vec<u8>, // eight 4 KiB banks, 2-7 only reachable in CGB mode
+    svbk: u8,
+    cgb: bool,
+    pub double_speed: bool,
+    speed_armed: bool,         // KEY1 bit 0, the switch happens on the next STOP
+    key0: u8,                  // written by the CGB boot ROM, bit 2 selects DMG mode
+    boot_rom: Option<Vec<u8>>, // mapped over the cartridge until BOOT is written
 }
 
 impl Mmu {
-    pub fn new(fp: &str) -> Self {
+    pub fn new(cart: Box<dyn Mbc + 'static>) -> Self {
         Self {
-            cart: make_mbc(fp),
+            cart,
             ppu: Ppu::new(),
-            wram: vec![0; 0x2000],
+            joypad: Joypad::new(),
+            apu: Apu::new(),
+            sgb: None,
+            wram: vec![0; 0x8000],
+            svbk: 0,
+            cgb: false,
+            double_speed: false,
+            speed_armed: false,
+            key0: 0,
+            boot_rom: None,
         }
     }
 
-    pub fn boot(fp: &str) -> Self {
+    pub fn with_boot_rom(cart: Box<dyn Mbc + 'static>, boot_rom: Vec<u8>) -> Self {
+        Self {
+            boot_rom: Some(boot_rom),
+            ..Self::new(cart)
+        }
+    }
+
+    pub fn boot(cart: Box<dyn Mbc + 'static>) -> Self {
         Self {
             ppu: Ppu::boot(),
-            ..Self::new(fp)
+            apu: Apu::boot(),
+            ..Self::new(cart)
+        }
+    }
+
+    /// Switches on CGB hardware: VRAM and WRAM banking and colour palettes.
+    pub fn set_cgb(&mut self) {
+        self.cgb = true;
+        self.ppu.cgb = true;
+    }
+
+    /// Drops back to DMG mode on CGB hardware, keeping the colour palettes for shading.
+    pub fn set_dmg_compat(&mut self) {
+        self.cgb = false;
+        self.svbk = 0;
+        self.ppu.set_compat();
+    }
+
+    /// Carries out an armed speed switch, returning false if there wasn't one.
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.speed_armed {
+            return false;
         }
+        self.speed_armed = false;
+        self.double_speed = !self.double_speed;
+        true
     }
 
     pub fn cycle(&mut self, cycles: u16) {
         self.ppu.cycle(cycles);
+        self.apu.cycle(cycles);
+        self.cart.cycle(cycles);
+    }
+
+    pub fn save_data(&self) -> Option<Vec<u8>> {
+        self.cart.save_data()
+    }
+
+    pub fn load_save(&mut self, data: &[u8]) {
+        self.cart.load_save(data);
+    }
+
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        self.cart.set_tilt(x, y);
+    }
+
+    pub fn take_events(&mut self) -> Vec<MbcEvent> {
+        self.cart.take_events()
     }
 
     pub fn read_byte(&self, addr: u16) -> u8 {
-        let a16: usize = addr as usize;
+        if let Some(b) = self.boot_rom_byte(addr) {
+            return b;
+        }
         match addr {
             0x0000..0x8000 => self.cart.read_byte(addr),
             0x8000..0xA000 => self.ppu.read_byte(addr),
             0xA000..0xC000 => self.cart.read_byte(addr),
-            0xC000..0xFE00 => self.wram[a16 & 0x1FFF],
+            0xC000..0xFE00 => self.wram[self.wram_addr(addr)],
             0xFE00..0xFF00 => self.ppu.read_byte(addr),
-            LCDC..=WX => self.ppu.read_byte(addr),
-            0xFF4D => 0xFF,
-            0xFF7F => 0xFF,
-            _ => {
-                panic!("UNSUPPORTED READ 0x{:04X}", addr)
-            }
+            P1 => match self.sgb.as_ref() {
+                Some(sgb) => sgb.read_p1(self.joypad.read_byte()),
+                None => self.joypad.read_byte(),
+            },
+            0xFF10..0xFF40 => self.apu.read_byte(addr),
+            LCDC..=WX | VBK | HDMA1..=HDMA5 | BCPS..=OCPD => self.ppu.read_byte(addr),
+            SVBK if self.cgb => 0xF8 | self.svbk,
+            SVBK => 0xFF,
+            // KEY0, RP, OPRI and the undocumented CGB registers aren't emulated
+            OPRI if self.cgb => 0xFE | self.ppu.opri as u8,
+            KEY0 | OPRI | 0xFF56 | 0xFF72..=0xFF77 => 0xFF,
+            KEY1 if self.cgb => 0x7E | (self.double_speed as u8) << 7 | self.speed_armed as u8,
+            KEY1 => 0xFF,
+            BOOT => 0xFF,
+            // unmapped IO reads as open bus
+            _ => 0xFF,
         }
     }
 
@@ -51,19 +133,58 @@ impl Mmu {
     }
 
     pub fn write_byte(&mut self, addr: u16, v: u8) {
-        let a16: usize = addr as usize;
         match addr {
             0x0000..0x8000 => self.cart.write_byte(addr, v),
             0x8000..0xA000 => self.ppu.write_byte(addr, v),
             0xA000..0xC000 => self.cart.write_byte(addr, v),
-            0xC000..0xFE00 => self.wram[a16 & 0x1FFF] = v,
+            0xC000..0xFE00 => {
+                let addr: usize = self.wram_addr(addr);
+                self.wram[addr] = v;
+            }
             0xFE00..0xFF00 => self.ppu.write_byte(addr, v),
-            LCDC..=WX => self.ppu.write_byte(addr, v),
-            0xFF4D => (),
-            0xFF7F => (),
-            _ => {
-                panic!("UNSUPPORTED WRITE 0x{:04X}", addr)
+            P1 => {
+                self.joypad.write_byte(v);
+                if let Some(sgb) = self.sgb.as_mut() {
+                    sgb.write_p1(v);
+                }
+            }
+            0xFF10..0xFF40 => self.apu.write_byte(addr, v),
+            LCDC..=WX | VBK | HDMA1..=HDMA5 | BCPS..=OCPD => self.ppu.write_byte(addr, v),
+            SVBK if self.cgb => self.svbk = v & 0x07,
+            SVBK => (),
+            // KEY0 and OPRI lock once the boot ROM is unmapped
+            KEY0 if self.cgb && self.boot_rom.is_some() => self.key0 = v,
+            OPRI if self.cgb && self.boot_rom.is_some() => self.ppu.opri = bit(v, 0),
+            KEY0 | OPRI | 0xFF56 | 0xFF72..=0xFF77 => (),
+            KEY1 if self.cgb => self.speed_armed = bit(v, 0),
+            KEY1 => (),
+            BOOT => {
+                // the CGB boot ROM writes KEY0 for DMG cartridges just before unmapping itself
+                let unmapped: bool = v != 0 && self.boot_rom.take().is_some();
+                if unmapped && self.cgb && bit(self.key0, 2) {
+                    self.set_dmg_compat();
+                }
             }
+            _ => (),
+        }
+    }
+
+    /// 0xD000-0xDFFF is switchable in CGB mode, where SVBK 0 still selects bank 1.
+    fn wram_addr(&self, addr: u16) -> usize {
+        let bank: usize = match addr & 0x1000 {
+            0 => 0,
+            _ if self.cgb => (self.svbk as usize).max(1),
+            _ => 1,
+        };
+        bank * 0x1000 + (addr as usize & 0x0FFF)
+    }
+
+    /// DMG boot ROMs cover 0x0000-0x00FF, CGB ones also 0x0200-0x08FF around the header.
+    fn boot_rom_byte(&self, addr: u16) -> Option<u8> {
+        let rom: &Vec<u8> = self.boot_rom.as_ref()?;
+        match addr {
+            0x0000..0x0100 | 0x0200..0x0900 => rom.get(addr as usize).copied(),
+            _ => None,
         }
     }
 
@@ -73,3 +194,73 @@ impl Mmu {
         self.write_byte(addr + 1, hi);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::mbc0::Mbc0;
+
+    fn cgb() -> Mmu {
+        let mut mmu: Mmu = Mmu::new(Box::new(Mbc0::new(vec![0; 0x8000])));
+        mmu.set_cgb();
+        mmu
+    }
+
+    #[test]
+    fn wram_banks() {
+        let mut mmu: Mmu = cgb();
+        for bank in 0..8 {
+            mmu.write_byte(SVBK, bank);
+            mmu.write_byte(0xD000, 0x10 | bank);
+            mmu.write_byte(0xC000, bank);
+        }
+        // SVBK 0 maps bank 1, and 0xC000-0xCFFF is always bank 0
+        mmu.write_byte(SVBK, 0);
+        assert_eq!((mmu.read_byte(SVBK), mmu.read_byte(0xD000)), (0xF8, 0x11));
+        mmu.write_byte(SVBK, 0xFD);
+        assert_eq!((mmu.read_byte(SVBK), mmu.read_byte(0xD000)), (0xFD, 0x15));
+        assert_eq!(mmu.read_byte(0xC000), 7);
+        // echo RAM follows the switched bank
+        assert_eq!(mmu.read_byte(0xF000), 0x15);
+
+        let mut dmg: Mmu = Mmu::new(Box::new(Mbc0::new(vec![0; 0x8000])));
+        dmg.write_byte(0xD000, 0x42);
+        dmg.write_byte(SVBK, 2);
+        assert_eq!((dmg.read_byte(SVBK), dmg.read_byte(0xD000)), (0xFF, 0x42));
+    }
+
+    #[test]
+    fn vram_banks() {
+        let mut mmu: Mmu = cgb();
+        mmu.write_byte(VBK, 1);
+        mmu.write_byte(0x8000, 0xAA);
+        assert_eq!(mmu.read_byte(VBK), 0xFF);
+        mmu.write_byte(VBK, 0);
+        mmu.write_byte(0x8000, 0x55);
+        assert_eq!((mmu.read_byte(VBK), mmu.read_byte(0x8000)), (0xFE, 0x55));
+        mmu.write_byte(VBK, 0xFF);
+        assert_eq!(mmu.read_byte(0x8000), 0xAA);
+    }
+
+    #[test]
+    fn palette_auto_increment() {
+        let mut mmu: Mmu = cgb();
+        for (cps, cpd) in [(BCPS, BCPD), (OCPS, OCPD)] {
+            // bit 7 steps the index after each write, wrapping at 0x3F
+            mmu.write_byte(cps, 0xBE);
+            mmu.write_byte(cpd, 0x11);
+            mmu.write_byte(cpd, 0x22);
+            mmu.write_byte(cpd, 0x33);
+            assert_eq!(mmu.read_byte(cps), 0xC1);
+            // reads don't step it
+            mmu.write_byte(cps, 0xBF);
+            assert_eq!((mmu.read_byte(cpd), mmu.read_byte(cpd)), (0x22, 0x22));
+            mmu.write_byte(cps, 0x00);
+            mmu.write_byte(cpd, 0x44);
+            mmu.write_byte(cpd, 0x55);
+            assert_eq!((mmu.read_byte(cps), mmu.read_byte(cpd)), (0x40, 0x55));
+            mmu.write_byte(cps, 0x3E);
+            assert_eq!(mmu.read_byte(cpd), 0x11);
+        }
+    }
+}
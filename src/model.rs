@@ -0,0 +1,49 @@
+use std::str::FromStr;
+
+/// The console being emulated, which decides the state the boot ROM hands over in.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum Model {
+    #[default]
+    Dmg,
+    Mgb,
+    Sgb,
+    Sgb2,
+    Cgb,
+}
+
+impl Model {
+    /// B, C, D, E, H, L, A, F as the boot ROM leaves them. Games tell models apart by A
+    /// (0x01 DMG/SGB, 0xFF MGB/SGB2, 0x11 CGB) and SGB from DMG by C.
+    pub fn registers(&self) -> [u8; 8] {
+        match self {
+            Model::Dmg => [0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D, 0x01, 0xB0],
+            Model::Mgb => [0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D, 0xFF, 0xB0],
+            Model::Sgb => [0x00, 0x14, 0x00, 0x00, 0xC0, 0x60, 0x01, 0x00],
+            Model::Sgb2 => [0x00, 0x14, 0x00, 0x00, 0xC0, 0x60, 0xFF, 0x00],
+            Model::Cgb => [0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D, 0x11, 0x80],
+        }
+    }
+
+    /// Size of this model's boot ROM image.
+    pub fn boot_rom_size(&self) -> usize {
+        match self {
+            Model::Cgb => 0x900,
+            _ => 0x100,
+        }
+    }
+}
+
+impl FromStr for Model {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "dmg" => Ok(Model::Dmg),
+            "mgb" => Ok(Model::Mgb),
+            "sgb" => Ok(Model::Sgb),
+            "sgb2" => Ok(Model::Sgb2),
+            "cgb" => Ok(Model::Cgb),
+            _ => Err(format!("unknown model {}", s)),
+        }
+    }
+}
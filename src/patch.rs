@@ -0,0 +1,396 @@
+use crate::mbc::LoadError;
+use std::path::{Path, PathBuf};
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const UPS_MAGIC: &[u8] = b"UPS1";
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+/// Looks for an `.ips`, `.ups` or `.bps` next to the ROM with the same stem.
+pub fn sibling(rom: &str) -> Option<PathBuf> {
+    ["ips", "ups", "bps"]
+        .iter()
+        .map(|ext| Path::new(rom).with_extension(ext))
+        .find(|path| path.is_file())
+}
+
+/// Applies an IPS, UPS or BPS patch to `rom`, detected by its magic bytes. UPS and BPS
+/// patches are rejected unless the source, target and patch CRC32s all match.
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, LoadError> {
+    if patch.starts_with(IPS_MAGIC) {
+        ips(rom, patch)
+    } else if patch.starts_with(UPS_MAGIC) {
+        ups(rom, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        bps(rom, patch)
+    } else {
+        Err(LoadError::Patch("unrecognised patch format".to_string()))
+    }
+}
+
+fn ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, LoadError> {
+    let mut out: Vec<u8> = rom.to_vec();
+    let mut p: Patch = Patch::new(patch, IPS_MAGIC.len());
+    loop {
+        if p.rest().starts_with(b"EOF") && matches!(p.rest().len(), 3 | 6) {
+            p.pos += 3;
+            break;
+        }
+        let offset: usize = p.be(3)?;
+        let len: usize = p.be(2)?;
+        // a zero length marks an RLE record: a 16-bit count and the byte to repeat
+        let (len, rle): (usize, Option<u8>) = match len {
+            0 => (p.be(2)?, Some(p.byte()?)),
+            len => (len, None),
+        };
+        if out.len() < offset + len {
+            out.resize(offset + len, 0);
+        }
+        match rle {
+            Some(b) => out[offset..offset + len].fill(b),
+            None => out[offset..offset + len].copy_from_slice(p.bytes(len)?),
+        }
+    }
+    // some IPS writers append the target size to truncate to
+    if p.rest().len() == 3 {
+        let size: usize = p.be(3)?;
+        out.truncate(size);
+    }
+    Ok(out)
+}
+
+fn ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, LoadError> {
+    let (body, crcs) = footer(patch)?;
+    check_crc("source", rom, crcs[0])?;
+    let mut p: Patch = Patch::new(body, UPS_MAGIC.len());
+    let source_size: usize = p.varint()?;
+    let target_size: usize = p.varint()?;
+    if source_size != rom.len() {
+        return Err(size_mismatch(source_size, rom.len()));
+    }
+    let mut out: Vec<u8> = rom.to_vec();
+    out.resize(target_size, 0);
+    let mut pos: usize = 0;
+    while !p.rest().is_empty() {
+        pos += p.varint()?;
+        // XOR bytes run until a zero, which also consumes one byte of output
+        loop {
+            let x: u8 = p.byte()?;
+            if pos < out.len() {
+                out[pos] ^= x;
+            }
+            pos += 1;
+            if x == 0 {
+                break;
+            }
+        }
+    }
+    check_crc("target", &out, crcs[1])?;
+    Ok(out)
+}
+
+fn bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, LoadError> {
+    let (body, crcs) = footer(patch)?;
+    check_crc("source", rom, crcs[0])?;
+    let mut p: Patch = Patch::new(body, BPS_MAGIC.len());
+    let source_size: usize = p.varint()?;
+    let target_size: usize = p.varint()?;
+    let metadata: usize = p.varint()?;
+    p.bytes(metadata)?;
+    if source_size != rom.len() {
+        return Err(size_mismatch(source_size, rom.len()));
+    }
+    let mut out: Vec<u8> = Vec::with_capacity(target_size);
+    let mut source_rel: usize = 0;
+    let mut target_rel: usize = 0;
+    while !p.rest().is_empty() {
+        let action: usize = p.varint()?;
+        let len: usize = (action >> 2) + 1;
+        if out.len() + len > target_size {
+            return Err(LoadError::Patch("BPS action overruns target".to_string()));
+        }
+        match action & 3 {
+            // SourceRead
+            0 => {
+                let start: usize = out.len();
+                let src: &[u8] = rom
+                    .get(start..start + len)
+                    .ok_or_else(|| LoadError::Patch("BPS source read out of range".to_string()))?;
+                out.extend_from_slice(src);
+            }
+            // TargetRead
+            1 => out.extend_from_slice(p.bytes(len)?),
+            // SourceCopy
+            2 => {
+                source_rel = p.relative(source_rel)?;
+                let src: &[u8] = rom
+                    .get(source_rel..source_rel + len)
+                    .ok_or_else(|| LoadError::Patch("BPS source copy out of range".to_string()))?;
+                out.extend_from_slice(src);
+                source_rel += len;
+            }
+            // TargetCopy, which may overlap the bytes it is writing
+            3 => {
+                target_rel = p.relative(target_rel)?;
+                if target_rel >= out.len() {
+                    return Err(LoadError::Patch("BPS target copy out of range".to_string()));
+                }
+                for _ in 0..len {
+                    out.push(out[target_rel]);
+                    target_rel += 1;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+    if out.len() != target_size {
+        return Err(size_mismatch(target_size, out.len()));
+    }
+    check_crc("target", &out, crcs[1])?;
+    Ok(out)
+}
+
+/// Splits off the 12-byte source/target/patch CRC32 footer shared by UPS and BPS.
+fn footer(patch: &[u8]) -> Result<(&[u8], [u32; 3]), LoadError> {
+    if patch.len() < 4 + 12 {
+        return Err(LoadError::Patch("patch truncated".to_string()));
+    }
+    let (body, footer) = patch.split_at(patch.len() - 12);
+    let crc = |i: usize| u32::from_le_bytes(footer[i * 4..i * 4 + 4].try_into().unwrap());
+    check_crc("patch", &patch[..patch.len() - 4], crc(2))?;
+    Ok((body, [crc(0), crc(1), crc(2)]))
+}
+
+fn check_crc(what: &str, data: &[u8], expected: u32) -> Result<(), LoadError> {
+    let actual: u32 = crc32(data);
+    if actual != expected {
+        return Err(LoadError::Patch(format!(
+            "{} CRC32 mismatch: expected {:08X}, got {:08X}",
+            what, expected, actual
+        )));
+    }
+    Ok(())
+}
+
+fn size_mismatch(expected: usize, actual: usize) -> LoadError {
+    LoadError::Patch(format!(
+        "size mismatch: expected {} bytes, got {}",
+        expected, actual
+    ))
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = !0;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+struct Patch<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Patch<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Self { data, pos }
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        &self.data[self.pos.min(self.data.len())..]
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], LoadError> {
+        let bytes: &[u8] = self
+            .rest()
+            .get(..len)
+            .ok_or_else(|| LoadError::Patch("patch truncated".to_string()))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, LoadError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn be(&mut self, len: usize) -> Result<usize, LoadError> {
+        Ok(self
+            .bytes(len)?
+            .iter()
+            .fold(0, |acc, &b| acc << 8 | b as usize))
+    }
+
+    /// UPS/BPS variable-length integer: 7 bits per byte, high bit ends the number.
+    fn varint(&mut self) -> Result<usize, LoadError> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let b: u8 = self.byte()?;
+            value += (b & 0x7F) as usize * shift;
+            if b & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift <<= 7;
+            value += shift;
+            if shift > 1 << 56 {
+                return Err(LoadError::Patch("varint overflow".to_string()));
+            }
+        }
+    }
+
+    /// BPS copy offset: a signed delta from the previous position, sign in the low bit.
+    fn relative(&mut self, from: usize) -> Result<usize, LoadError> {
+        let delta: usize = self.varint()?;
+        let offset: usize = delta >> 1;
+        let to: Option<usize> = if delta & 1 == 1 {
+            from.checked_sub(offset)
+        } else {
+            from.checked_add(offset)
+        };
+        to.ok_or_else(|| LoadError::Patch("BPS copy offset out of range".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(mut v: usize, out: &mut Vec<u8>) {
+        loop {
+            let b: u8 = (v & 0x7F) as u8;
+            v >>= 7;
+            if v == 0 {
+                out.push(0x80 | b);
+                return;
+            }
+            out.push(b);
+            v -= 1;
+        }
+    }
+
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend(crc32(source).to_le_bytes());
+        patch.extend(crc32(target).to_le_bytes());
+        patch.extend(crc32(&patch).to_le_bytes());
+        patch
+    }
+
+    fn ups_body(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut patch: Vec<u8> = UPS_MAGIC.to_vec();
+        varint(source.len(), &mut patch);
+        varint(target.len(), &mut patch);
+        let len: usize = source.len().max(target.len());
+        let xor = |i: usize| source.get(i).unwrap_or(&0) ^ target.get(i).unwrap_or(&0);
+        let (mut i, mut last) = (0, 0);
+        while i < len {
+            if xor(i) == 0 {
+                i += 1;
+                continue;
+            }
+            varint(i - last, &mut patch);
+            while i < len && xor(i) != 0 {
+                patch.push(xor(i));
+                i += 1;
+            }
+            patch.push(0);
+            i += 1;
+            last = i;
+        }
+        patch
+    }
+
+    fn source() -> Vec<u8> {
+        (0..=0xFF).collect()
+    }
+
+    #[test]
+    fn ips_records() {
+        let mut patch: Vec<u8> = IPS_MAGIC.to_vec();
+        patch.extend([0x00, 0x00, 0x02, 0x00, 0x02, 0xAA, 0xBB]);
+        // RLE record running past the end of the ROM
+        patch.extend([0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x04, 0xCC]);
+        patch.extend(b"EOF");
+        let out: Vec<u8> = apply(&source(), &patch).unwrap();
+        assert_eq!(out.len(), 0x104);
+        assert_eq!(out[..4], [0x00, 0x01, 0xAA, 0xBB]);
+        assert_eq!(out[0x100..], [0xCC; 4]);
+    }
+
+    #[test]
+    fn ips_truncation() {
+        let mut patch: Vec<u8> = IPS_MAGIC.to_vec();
+        patch.extend([0x00, 0x00, 0x00, 0x00, 0x01, 0xAA]);
+        patch.extend(b"EOF");
+        patch.extend([0x00, 0x00, 0x80]);
+        let out: Vec<u8> = apply(&source(), &patch).unwrap();
+        assert_eq!(out.len(), 0x80);
+        assert_eq!(out[..2], [0xAA, 0x01]);
+    }
+
+    #[test]
+    fn ups_round_trip() {
+        let source: Vec<u8> = source();
+        let mut target: Vec<u8> = source.clone();
+        target[0x10..0x14].copy_from_slice(b"GAME");
+        target[0xFF] = 0;
+        target.extend([0x12, 0x00, 0x34]);
+        let patch: Vec<u8> = with_footer(ups_body(&source, &target), &source, &target);
+        assert_eq!(apply(&source, &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn bps_round_trip() {
+        let source: Vec<u8> = source();
+        let mut target: Vec<u8> = source[..8].to_vec();
+        target.extend(b"HELLO");
+        target.extend(&source[0x20..0x28]);
+        target.extend(&source[0x04..0x08]);
+        // overlapping copy from earlier in the target
+        for i in 0..10 {
+            target.push(target[0x10 + i]);
+        }
+        let mut patch: Vec<u8> = BPS_MAGIC.to_vec();
+        varint(source.len(), &mut patch);
+        varint(target.len(), &mut patch);
+        varint(0, &mut patch);
+        let action =
+            |len: usize, kind: usize, patch: &mut Vec<u8>| varint((len - 1) << 2 | kind, patch);
+        action(8, 0, &mut patch);
+        action(5, 1, &mut patch);
+        patch.extend(b"HELLO");
+        action(8, 2, &mut patch);
+        varint(0x20 << 1, &mut patch);
+        action(4, 2, &mut patch);
+        varint((0x28 - 0x04) << 1 | 1, &mut patch);
+        action(10, 3, &mut patch);
+        varint(0x10 << 1, &mut patch);
+        let patch: Vec<u8> = with_footer(patch, &source, &target);
+        assert_eq!(apply(&source, &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn crc_mismatch() {
+        let source: Vec<u8> = source();
+        let mut target: Vec<u8> = source.clone();
+        target[0] = 0xFF;
+        let body: Vec<u8> = ups_body(&source, &target);
+        let patch: Vec<u8> = with_footer(body.clone(), &source, &target);
+        let err = |patch: &[u8], rom: &[u8]| match apply(rom, patch) {
+            Err(LoadError::Patch(msg)) => msg,
+            _ => panic!("patch applied"),
+        };
+        assert!(err(&patch, &target).starts_with("source CRC32"));
+        let wrong: Vec<u8> = with_footer(body, &source, &source);
+        assert!(err(&wrong, &source).starts_with("target CRC32"));
+        let mut corrupt: Vec<u8> = patch.clone();
+        corrupt[4] ^= 1;
+        assert!(err(&corrupt, &source).starts_with("patch CRC32"));
+    }
+}
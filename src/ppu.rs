@@ -1,8 +1,18 @@
 use crate::ppu::PpuMode::*;
 use crate::utils::*;
+use std::collections::VecDeque;
+
+/// RGB555 colours for the four DMG shades, lightest first.
+pub const DMG_PALETTE: [u16; 4] = [
+    rgb555(155, 188, 15),
+    rgb555(139, 172, 15),
+    rgb555(48, 98, 48),
+    rgb555(15, 56, 15),
+];
 
 pub struct Ppu {
-    vram: Vec<u8>,
+    vram: Vec<u8>, // two 8 KiB banks, the second only reachable in CGB mode
+    vbk: u8,
     oam: Vec<u8>,
     ppu_mode: PpuMode,
     pub ly: u8,
@@ -16,18 +26,47 @@ pub struct Ppu {
     lcdc: u8,
     obp0: u8,
     obp1: u8,
-    pub display_buffer: [u8; 160 * 144],
+    bcps: u8,
+    ocps: u8,
+    bg_palettes: [u8; 64], // 8 palettes of 4 RGB555 colours
+    obj_palettes: [u8; 64],
+    pub cgb: bool,
+    pub compat: bool, // DMG mode on CGB hardware, shades go through palettes BG0, OBJ0 and OBJ1
+    pub opri: bool,   // OPRI bit 0, DMG object priority in CGB mode
+    /// RGB555 pixels.
+    pub display_buffer: [u16; 160 * 144],
+    /// DMG shades (0-3) after the palette registers, which the SGB colourises.
+    pub shade_buffer: [u8; 160 * 144],
+    objs: Vec<Sprite>, // objects on this line not fetched yet
+    bg_fifo: VecDeque<BgPixel>,
+    obj_fifo: VecDeque<ObjPixel>,
+    fetcher: Fetcher,
+    obj_fetch: Option<u8>, // dots left in an object fetch
+    lx: u8,                // pixels sent to the LCD this line
+    discard: u8,           // pixels still to drop for fine scroll
+    window_line: u8,
+    window_drawn: bool,
+    window_wrap: bool, // this line starts inside the window
+    wy_hit: bool,
+    wx_wrap: bool,
     dots: u16,
     pub dma: bool,
     pub dma_src: u16,
+    pub hdma_src: u16,
+    hdma_dst: u16,  // offset into the VRAM bank
+    hdma_len: u8,   // 16-byte blocks left minus one, 0x7F once finished
+    pub hdma: bool, // an H-Blank transfer is running
+    pub gdma: bool, // a general-purpose transfer is waiting for the CPU
     pub int_line: bool,
     pub vblank: bool,
+    pub hblank: bool,
 }
 
 impl Ppu {
     pub fn new() -> Self {
         Self {
-            vram: vec![0; 0x2000],
+            vram: vec![0; 0x4000],
+            vbk: 0,
             oam: vec![0; 0xA0],
             ppu_mode: Mode2,
             ly: 0,
@@ -41,12 +80,38 @@ impl Ppu {
             obp1: 0,
             lcdc: 0,
             stat: 0,
-            display_buffer: [0; 160 * 144],
+            bcps: 0,
+            ocps: 0,
+            bg_palettes: [0xFF; 64],
+            obj_palettes: [0xFF; 64],
+            cgb: false,
+            compat: false,
+            opri: false,
+            display_buffer: [DMG_PALETTE[0]; 160 * 144],
+            shade_buffer: [0; 160 * 144],
+            objs: Vec::with_capacity(10),
+            bg_fifo: VecDeque::with_capacity(16),
+            obj_fifo: VecDeque::with_capacity(8),
+            fetcher: Fetcher::default(),
+            obj_fetch: None,
+            lx: 0,
+            discard: 0,
+            window_line: 0,
+            window_drawn: false,
+            window_wrap: false,
+            wy_hit: false,
+            wx_wrap: false,
             dots: 0,
             dma: false,
             dma_src: 0,
+            hdma_src: 0,
+            hdma_dst: 0,
+            hdma_len: 0x7F,
+            hdma: false,
+            gdma: false,
             int_line: false,
             vblank: false,
+            hblank: false,
         }
     }
 
@@ -59,57 +124,73 @@ impl Ppu {
         }
     }
 
+    /// Turns the CGB's colour hardware back into a DMG PPU, as KEY0 does.
+    pub fn set_compat(&mut self) {
+        self.cgb = false;
+        self.compat = true;
+        self.vbk = 0;
+    }
+
+    /// Fills BG0, OBJ0 and OBJ1 with the same colours, like the boot ROM's palette pick.
+    pub fn set_compat_palettes(&mut self, colours: [u16; 4]) {
+        let bytes: Vec<u8> = colours.iter().flat_map(|c| c.to_le_bytes()).collect();
+        self.bg_palettes[..8].copy_from_slice(&bytes);
+        self.obj_palettes[..8].copy_from_slice(&bytes);
+        self.obj_palettes[8..16].copy_from_slice(&bytes);
+    }
+
     pub fn cycle(&mut self, cycles: u16) {
         self.vblank = false;
+        self.hblank = false;
         if !bit(self.lcdc, 7) {
             return;
         }
-        let mut available_dots = cycles * 4;
+        for _ in 0..cycles * 4 {
+            self.dot();
+        }
+    }
 
-        while available_dots > 0 {
-            let dots = if available_dots >= 80 {
-                80
-            } else {
-                available_dots
-            };
-            self.dots += dots;
-            available_dots -= dots;
-
-            if self.dots >= 456 {
-                self.dots -= 456;
-                self.ly = (self.ly + 1) % 154;
-                if self.ly == self.lyc {
-                    // println!("LYC==LY");
-                    self.stat |= 4;
-                    // println!("STAT: 0b{:08b}", self.stat);
-                } else {
-                    self.stat &= !4;
-                }
-                if self.ly == 144 && self.ppu_mode != Mode1 {
-                    self.vblank = true;
-                    self.ppu_mode = Mode1;
-                }
+    /// Mode 2 takes 80 dots, Mode 3 lasts until 160 pixels are out and Mode 0 pads the
+    /// line to 456 dots.
+    fn dot(&mut self) {
+        if self.ly < 144 {
+            if self.ppu_mode != Mode3 && self.dots == 80 {
+                self.ppu_mode = Mode3;
+                self.oam_scan();
+                self.start_line();
             }
-
-            if self.ly < 144 {
-                if self.dots <= 80 {
-                    if self.ppu_mode != Mode2 {
-                        self.ppu_mode = Mode2;
-                    }
-                } else if self.dots <= 80 + 172 {
-                    if self.ppu_mode != Mode3 {
-                        self.ppu_mode = Mode3;
-                        self.draw_bg();
-                    }
-                } else {
-                    if self.ppu_mode != Mode0 {
-                        self.ppu_mode = Mode0;
+            if self.ppu_mode == Mode3 {
+                self.render_dot();
+                if self.lx == 160 {
+                    self.ppu_mode = Mode0;
+                    self.hblank = true;
+                    if self.window_drawn {
+                        self.window_line += 1;
                     }
                 }
             }
-            self.stat = (self.stat & 0b1111_1100) | (self.ppu_mode as u8);
-            self.set_intline();
         }
+        self.dots += 1;
+        if self.dots == 456 {
+            self.dots = 0;
+            self.ly = (self.ly + 1) % 154;
+            if self.ly == self.lyc {
+                self.stat |= 4;
+            } else {
+                self.stat &= !4;
+            }
+            if self.ly == 144 {
+                self.vblank = true;
+                self.ppu_mode = Mode1;
+                self.window_line = 0;
+                self.wy_hit = false;
+                self.wx_wrap = false;
+            } else if self.ly < 144 {
+                self.ppu_mode = Mode2;
+            }
+        }
+        self.stat = (self.stat & 0b1111_1100) | (self.ppu_mode as u8);
+        self.set_intline();
     }
 
     pub fn dma_transfer(&mut self, byte: u8, offset: u8) {
@@ -117,13 +198,32 @@ impl Ppu {
         self.oam[offset as usize] = byte;
     }
 
+    /// Writes the next HDMA byte to VRAM, ending the transfer after the last block.
+    pub fn hdma_transfer(&mut self, byte: u8) {
+        self.vram[self.vbk as usize * 0x2000 + self.hdma_dst as usize] = byte;
+        self.hdma_src = self.hdma_src.wrapping_add(1);
+        self.hdma_dst = (self.hdma_dst + 1) & 0x1FFF;
+        if self.hdma_dst & 0x0F == 0 {
+            self.hdma_len = self.hdma_len.wrapping_sub(1) & 0x7F;
+            if self.hdma_len == 0x7F {
+                self.hdma = false;
+                self.gdma = false;
+            }
+        }
+    }
+
+    /// 16-byte blocks left in the current VRAM DMA.
+    pub fn hdma_blocks(&self) -> u16 {
+        self.hdma_len as u16 + 1
+    }
+
     pub fn read_byte(&self, addr: u16) -> u8 {
         match addr {
             0x8000..0xA000 => {
                 if self.ppu_mode == Mode3 && bit(self.lcdc, 7) {
                     0xFF
                 } else {
-                    self.read_vram(addr)
+                    self.read_vram(self.vbk, addr)
                 }
             }
             0xFE00..0xFEA0 => {
@@ -153,6 +253,14 @@ impl Ppu {
             STAT => self.stat,
             OBP0 => self.obp0,
             OBP1 => self.obp1,
+            VBK if self.cgb => 0xFE | self.vbk,
+            BCPS if self.cgb => self.bcps | 0x40,
+            BCPD if self.cgb => self.bg_palettes[(self.bcps & 0x3F) as usize],
+            OCPS if self.cgb => self.ocps | 0x40,
+            OCPD if self.cgb => self.obj_palettes[(self.ocps & 0x3F) as usize],
+            // bit 7 reads 0 while an H-Blank transfer is running
+            HDMA5 if self.cgb => (!self.hdma as u8) << 7 | self.hdma_len,
+            VBK | HDMA1..=HDMA5 | BCPS..=OCPD => 0xFF,
             _ => todo!("UNSUPPORTED READ 0x{:04X}", addr),
         }
     }
@@ -201,41 +309,325 @@ impl Ppu {
             STAT => self.stat = b,
             OBP0 => self.obp0 = b,
             OBP1 => self.obp1 = b,
+            VBK if self.cgb => self.vbk = b & 1,
+            BCPS if self.cgb => self.bcps = b & 0xBF,
+            BCPD if self.cgb => {
+                self.bg_palettes[(self.bcps & 0x3F) as usize] = b;
+                self.bcps = increment_cps(self.bcps);
+            }
+            OCPS if self.cgb => self.ocps = b & 0xBF,
+            OCPD if self.cgb => {
+                self.obj_palettes[(self.ocps & 0x3F) as usize] = b;
+                self.ocps = increment_cps(self.ocps);
+            }
+            HDMA1 if self.cgb => self.hdma_src = (self.hdma_src & 0x00F0) | (b as u16) << 8,
+            HDMA2 if self.cgb => self.hdma_src = (self.hdma_src & 0xFF00) | (b & 0xF0) as u16,
+            HDMA3 if self.cgb => {
+                self.hdma_dst = (self.hdma_dst & 0x00F0) | ((b & 0x1F) as u16) << 8
+            }
+            HDMA4 if self.cgb => self.hdma_dst = (self.hdma_dst & 0x1F00) | (b & 0xF0) as u16,
+            HDMA5 if self.cgb => {
+                if self.hdma && !bit(b, 7) {
+                    // cancelling leaves the remaining length readable
+                    self.hdma = false;
+                } else {
+                    self.hdma_len = b & 0x7F;
+                    self.hdma = bit(b, 7);
+                    self.gdma = !bit(b, 7);
+                }
+            }
+            VBK | HDMA1..=HDMA5 | BCPS..=OCPD => (),
             _ => todo!("UNSUPPORTED WRITE 0x{:04X}", addr),
         }
     }
 
-    fn draw_bg(&mut self) {
-        let scy: u8 = self.scy.wrapping_add(self.ly);
-        let tiley: u16 = (scy as u16 / 8) % 32;
-        for lx in 0..160u8 {
-            let scx: u8 = self.scx.wrapping_add(lx);
-            let tilex: u16 = (scx as u16 / 8) % 32;
-            let py: u8 = scy % 8;
-            let px: u8 = scx % 8;
-            let tmap_base: u16 = 0x9800 | (bit(self.lcdc, 3) as u16) << 10;
-            let tile_id: u8 = self.read_vram(tmap_base + tiley * 0x20 + tilex);
-            let tile_addr: u16 = if bit(self.lcdc, 4) {
-                0x8000 + 0x10 * tile_id as u16
+    /// Advances one dot of Mode 3: the BG fetcher fills the BG FIFO a tile at a time and
+    /// each dot shifts one pixel out to the LCD, stalling while objects are fetched.
+    fn render_dot(&mut self) {
+        if let Some(dots) = self.obj_fetch {
+            if dots > 1 {
+                self.obj_fetch = Some(dots - 1);
             } else {
-                0x9000 + 0x10 * (tile_id as i8 as i16 + 128) as u16
-            };
-            let tile_lo: u8 = self.read_vram(tile_addr + 2 * py as u16);
-            let tile_hi: u8 = self.read_vram(tile_addr + 2 * py as u16 + 1);
-            let colour_lsb = bit(tile_lo, 7 - px);
-            let colour_msb = bit(tile_hi, 7 - px);
-            let colour: u8 = match (colour_msb, colour_lsb) {
-                (false, false) => self.bgp & 3,
-                (false, true) => (self.bgp >> 2) & 3,
-                (true, false) => (self.bgp >> 4) & 3,
-                (true, true) => (self.bgp >> 6) & 3,
+                self.obj_fetch = None;
+                self.fetch_obj();
+            }
+            return;
+        }
+        if self.window_trigger() {
+            self.window_drawn = true;
+            self.bg_fifo.clear();
+            self.fetcher = Fetcher {
+                window: true,
+                started: true,
+                ..Fetcher::default()
             };
-            self.display_buffer[self.ly as usize * 160 + lx as usize] = colour;
+            // WX < 7 clips the leftmost window columns
+            if self.lx == 0 {
+                self.discard = match self.window_wrap {
+                    true => 0,
+                    false => 7u8.saturating_sub(self.wx),
+                };
+            }
+        }
+        if self.discard == 0 && self.obj_hit().is_some() {
+            // the object fetch waits for the BG fetcher to finish its tile, then takes
+            // six dots counting this one
+            if self.fetcher.step == FetchStep::Push && !self.bg_fifo.is_empty() {
+                self.obj_fetch = Some(5);
+            } else {
+                self.fetch_dot();
+            }
+            return;
+        }
+        // a tile pushed this dot only starts shifting out on the next
+        let bg: Option<BgPixel> = self.bg_fifo.pop_front();
+        self.fetch_dot();
+        let Some(bg) = bg else {
+            return;
+        };
+        // SCX fine scroll drops the first pixels of the line
+        if self.discard > 0 {
+            self.discard -= 1;
+            return;
+        }
+        let obj: Option<ObjPixel> = self.obj_fifo.pop_front();
+        self.output(bg, obj);
+        self.lx += 1;
+    }
+
+    fn window_trigger(&self) -> bool {
+        // the window waits for the line's first tile
+        if self.fetcher.window || !bit(self.lcdc, 5) || !self.wy_hit || self.bg_fifo.is_empty() {
+            return false;
+        }
+        if !self.cgb && !bit(self.lcdc, 0) {
+            return false;
+        }
+        // WX=166 doesn't show on its own line but covers the whole of the next one
+        self.window_wrap || (self.wx != 166 && self.lx as u16 + 7 >= self.wx as u16)
+    }
+
+    /// The first object reached at this X, if objects are on.
+    fn obj_hit(&self) -> Option<usize> {
+        if !bit(self.lcdc, 1) {
+            return None;
+        }
+        self.objs.iter().position(|obj| obj.x <= self.lx + 8)
+    }
+
+    /// Tile number and low byte take two dots each and the high byte one, then the
+    /// fetcher pushes 8 pixels on the first dot the BG FIFO is empty.
+    fn fetch_dot(&mut self) {
+        let step: FetchStep = self.fetcher.step;
+        if step == FetchStep::Push {
+            if self.bg_fifo.is_empty() {
+                self.push_tile();
+            }
+            return;
+        }
+        self.fetcher.wait = !self.fetcher.wait && step != FetchStep::High;
+        if self.fetcher.wait {
+            return;
+        }
+        let y: u8 = if self.fetcher.window {
+            self.window_line
+        } else {
+            self.ly.wrapping_add(self.scy)
+        };
+        match step {
+            FetchStep::Tile => {
+                let (map, x) = if self.fetcher.window {
+                    (bit(self.lcdc, 6), self.fetcher.x)
+                } else {
+                    (
+                        bit(self.lcdc, 3),
+                        (self.scx >> 3).wrapping_add(self.fetcher.x) & 0x1F,
+                    )
+                };
+                let map_addr: u16 =
+                    0x9800 | ((map as u16) << 10) | ((y as u16 / 8) * 0x20) | x as u16;
+                self.fetcher.tile = self.read_vram(0, map_addr);
+                self.fetcher.attrs = if self.cgb {
+                    self.read_vram(1, map_addr)
+                } else {
+                    0
+                };
+                self.fetcher.step = FetchStep::Low;
+            }
+            FetchStep::Low | FetchStep::High => {
+                let attrs: u8 = self.fetcher.attrs;
+                let tile_addr: u16 = if bit(self.lcdc, 4) {
+                    0x8000 + 0x10 * self.fetcher.tile as u16
+                } else {
+                    0x9000u16.wrapping_add((self.fetcher.tile as i8 as i16 * 0x10) as u16)
+                };
+                let row: u16 = if bit(attrs, 6) { 7 - y % 8 } else { y % 8 } as u16;
+                let addr: u16 = tile_addr + 2 * row;
+                if step == FetchStep::Low {
+                    self.fetcher.lo = self.read_vram(bit(attrs, 3) as u8, addr);
+                    self.fetcher.step = FetchStep::High;
+                } else {
+                    self.fetcher.hi = self.read_vram(bit(attrs, 3) as u8, addr + 1);
+                    self.fetcher.step = FetchStep::Push;
+                }
+            }
+            FetchStep::Push => unreachable!(),
+        }
+    }
+
+    fn push_tile(&mut self) {
+        self.fetcher.step = FetchStep::Tile;
+        // the first fetch of a line is thrown away and made again
+        if !self.fetcher.started {
+            self.fetcher.started = true;
+            return;
+        }
+        let attrs: u8 = self.fetcher.attrs;
+        for col in 0..8 {
+            let px: u8 = if bit(attrs, 5) { col } else { 7 - col };
+            self.bg_fifo.push_back(BgPixel {
+                colour: tile_colour(self.fetcher.lo, self.fetcher.hi, px),
+                attrs,
+            });
+        }
+        self.fetcher.x = self.fetcher.x.wrapping_add(1);
+    }
+
+    /// Fetches the object at this X and mixes it into the object FIFO. Pixels already
+    /// there keep their place unless transparent, or in CGB mode from a later OAM entry.
+    fn fetch_obj(&mut self) {
+        let Some(i) = self.obj_hit() else {
+            return;
+        };
+        let obj: Sprite = self.objs.remove(i);
+        let height: u8 = if bit(self.lcdc, 2) { 16 } else { 8 };
+        let mut row: u8 = self.ly.wrapping_sub(obj.y.wrapping_sub(16));
+        if bit(obj.flags, 6) {
+            row = height - 1 - row;
+        }
+        let tile: u8 = if height == 16 {
+            obj.tile & 0xFE
+        } else {
+            obj.tile
+        };
+        let bank: u8 = (self.cgb && bit(obj.flags, 3)) as u8;
+        let tile_addr: u16 = 0x8000 + 0x10 * tile as u16 + 2 * row as u16;
+        let lo: u8 = self.read_vram(bank, tile_addr);
+        let hi: u8 = self.read_vram(bank, tile_addr + 1);
+        while self.obj_fifo.len() < 8 {
+            self.obj_fifo.push_back(ObjPixel::default());
+        }
+        // objects hanging off the left edge lose their first columns
+        let skip: u8 = self.lx + 8 - obj.x;
+        for col in skip..8 {
+            let px: u8 = if bit(obj.flags, 5) { col } else { 7 - col };
+            let colour: u8 = tile_colour(lo, hi, px);
+            let slot: &mut ObjPixel = &mut self.obj_fifo[(col - skip) as usize];
+            if colour != 0
+                && (slot.colour == 0 || (self.cgb && !self.opri && obj.index < slot.index))
+            {
+                *slot = ObjPixel {
+                    colour,
+                    flags: obj.flags,
+                    index: obj.index,
+                };
+            }
+        }
+    }
+
+    /// Mixes a BG and object pixel with the palettes as they are right now.
+    fn output(&mut self, bg: BgPixel, obj: Option<ObjPixel>) {
+        let px: usize = self.ly as usize * 160 + self.lx as usize;
+        // on DMG, LCDC bit 0 clear blanks the BG, which then never hides objects.
+        // in CGB mode it puts every object above the BG instead
+        let blank: bool = !self.cgb && !bit(self.lcdc, 0);
+        let bg_colour: u8 = if blank { 0 } else { bg.colour };
+        let obj: Option<ObjPixel> = obj.filter(|obj| {
+            let bg_wins: bool = bg_colour != 0
+                && (bit(obj.flags, 7) || bit(bg.attrs, 7))
+                && (!self.cgb || bit(self.lcdc, 0));
+            obj.colour != 0 && bit(self.lcdc, 1) && !bg_wins
+        });
+        let (colour, index): (u16, u8) = match obj {
+            Some(obj) if self.cgb => (
+                cgb_colour(&self.obj_palettes, obj.flags & 0x07, obj.colour),
+                0,
+            ),
+            Some(obj) => {
+                let obp: u8 = if bit(obj.flags, 4) {
+                    self.obp1
+                } else {
+                    self.obp0
+                };
+                let index: u8 = shade(obp, obj.colour);
+                let palette: u8 = bit(obj.flags, 4) as u8;
+                (self.dmg_colour(&self.obj_palettes, palette, index), index)
+            }
+            None if self.cgb => (cgb_colour(&self.bg_palettes, bg.attrs & 0x07, bg_colour), 0),
+            None if blank => (self.dmg_colour(&self.bg_palettes, 0, 0), 0),
+            None => {
+                let index: u8 = shade(self.bgp, bg_colour);
+                (self.dmg_colour(&self.bg_palettes, 0, index), index)
+            }
+        };
+        self.display_buffer[px] = colour;
+        self.shade_buffer[px] = index;
+    }
+
+    /// Sets up Mode 3 for the current line.
+    fn start_line(&mut self) {
+        if self.ly == self.wy {
+            self.wy_hit = true;
+        }
+        let window: bool = bit(self.lcdc, 5) && self.wy_hit && (self.cgb || bit(self.lcdc, 0));
+        self.window_wrap = window && self.wx_wrap;
+        self.wx_wrap = window && self.wx == 166 && !self.wx_wrap;
+        self.window_drawn = false;
+        self.lx = 0;
+        self.discard = self.scx & 0x07;
+        self.fetcher = Fetcher::default();
+        self.bg_fifo.clear();
+        self.obj_fifo.clear();
+        self.obj_fetch = None;
+    }
+
+    fn oam_scan(&mut self) {
+        let height: u8 = if bit(self.lcdc, 2) { 16 } else { 8 };
+        self.objs.clear();
+        for (i, obj) in self.oam.chunks_exact(4).enumerate() {
+            let top: i16 = obj[0] as i16 - 16;
+            let ly: i16 = self.ly as i16;
+            if ly >= top && ly < top + height as i16 {
+                self.objs.push(Sprite {
+                    index: i as u8,
+                    y: obj[0],
+                    x: obj[1],
+                    tile: obj[2],
+                    flags: obj[3],
+                });
+                if self.objs.len() == 10 {
+                    break;
+                }
+            }
+        }
+        // DMG priority: smaller X wins, ties go to the earlier OAM entry (stable sort).
+        // CGB mode goes by OAM order alone unless OPRI asks for DMG priority
+        if !self.cgb || self.opri {
+            self.objs.sort_by_key(|obj| obj.x);
         }
     }
 
-    fn read_vram(&self, addr: u16) -> u8 {
-        self.vram[addr as usize - 0x8000]
+    /// A DMG shade, colourised by the CGB palettes in compatibility mode.
+    fn dmg_colour(&self, palettes: &[u8; 64], palette: u8, index: u8) -> u16 {
+        if self.compat {
+            cgb_colour(palettes, palette, index)
+        } else {
+            DMG_PALETTE[index as usize]
+        }
+    }
+
+    fn read_vram(&self, bank: u8, addr: u16) -> u8 {
+        self.vram[bank as usize * 0x2000 + addr as usize - 0x8000]
     }
 
     fn set_intline(&mut self) {
@@ -247,10 +639,80 @@ impl Ppu {
     }
 
     fn write_vram(&mut self, addr: u16, b: u8) {
-        self.vram[addr as usize - 0x8000] = b;
+        self.vram[self.vbk as usize * 0x2000 + addr as usize - 0x8000] = b;
+    }
+}
+
+fn tile_colour(lo: u8, hi: u8, px: u8) -> u8 {
+    (bit(hi, px) as u8) << 1 | bit(lo, px) as u8
+}
+
+fn shade(palette: u8, colour: u8) -> u8 {
+    (palette >> (colour * 2)) & 3
+}
+
+fn cgb_colour(palettes: &[u8; 64], palette: u8, colour: u8) -> u16 {
+    let i: usize = (palette as usize * 4 + colour as usize) * 2;
+    (palettes[i] as u16 | (palettes[i + 1] as u16) << 8) & 0x7FFF
+}
+
+/// BCPS/OCPS bit 7 steps the index on to the next byte after each data write.
+fn increment_cps(cps: u8) -> u8 {
+    if bit(cps, 7) {
+        0x80 | (cps + 1) & 0x3F
+    } else {
+        cps
     }
 }
 
+pub const fn rgb555(r: u8, g: u8, b: u8) -> u16 {
+    (r as u16 >> 3) | (g as u16 >> 3) << 5 | (b as u16 >> 3) << 10
+}
+
+#[derive(Copy, Clone)]
+struct Sprite {
+    index: u8, // OAM entry, which decides priority in CGB mode
+    y: u8,
+    x: u8,
+    tile: u8,
+    flags: u8,
+}
+
+#[derive(Copy, Clone)]
+struct BgPixel {
+    colour: u8,
+    attrs: u8, // CGB tile attributes
+}
+
+#[derive(Copy, Clone, Default)]
+struct ObjPixel {
+    colour: u8,
+    flags: u8,
+    index: u8,
+}
+
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Default)]
+enum FetchStep {
+    #[default]
+    Tile,
+    Low,
+    High,
+    Push,
+}
+
+#[derive(Copy, Clone, Default)]
+struct Fetcher {
+    step: FetchStep,
+    wait: bool, // the first dot of a two-dot step
+    x: u8,      // tile column, from SCX or the window's left edge
+    window: bool,
+    started: bool, // set once the line's first, discarded, fetch is done
+    tile: u8,
+    attrs: u8,
+    lo: u8,
+    hi: u8,
+}
+
 #[derive(Eq, PartialEq, Copy, Clone, Debug)]
 enum PpuMode {
     Mode0,
@@ -258,3 +720,289 @@ enum PpuMode {
     Mode2,
     Mode3,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LCDC_OBJ: u8 = 0x93; // LCD, 0x8000 tiles, objects and BG on
+
+    /// A PPU with the LCD off, so VRAM and OAM take writes, and identity palettes.
+    fn ppu() -> Ppu {
+        let mut ppu: Ppu = Ppu::new();
+        for palette in [BGP, OBP0, OBP1] {
+            ppu.write_byte(palette, 0xE4);
+        }
+        ppu
+    }
+
+    /// Fills every row of `tile` with the two bit planes.
+    fn tile(ppu: &mut Ppu, tile: u8, lo: u8, hi: u8) {
+        for row in 0..8 {
+            let addr: u16 = 0x8000 + 0x10 * tile as u16 + 2 * row;
+            ppu.write_byte(addr, lo);
+            ppu.write_byte(addr + 1, hi);
+        }
+    }
+
+    fn obj(ppu: &mut Ppu, index: u16, y: u8, x: u8, tile: u8, flags: u8) {
+        for (i, b) in [y, x, tile, flags].into_iter().enumerate() {
+            ppu.write_byte(0xFE00 + index * 4 + i as u16, b);
+        }
+    }
+
+    fn lines(ppu: &mut Ppu, lines: u8) {
+        for _ in 0..lines {
+            ppu.cycle(114);
+        }
+    }
+
+    /// Turns the LCD on and renders a frame.
+    fn frame(ppu: &mut Ppu, lcdc: u8) {
+        ppu.write_byte(LCDC, lcdc);
+        lines(ppu, 154);
+    }
+
+    fn shade_at(ppu: &Ppu, x: usize, y: usize) -> u8 {
+        ppu.shade_buffer[y * 160 + x]
+    }
+
+    #[test]
+    fn ten_objects_per_line() {
+        let mut ppu: Ppu = ppu();
+        tile(&mut ppu, 1, 0xFF, 0xFF);
+        // an object off the left edge still takes one of the ten slots
+        obj(&mut ppu, 0, 16, 0, 1, 0);
+        for i in 1..11 {
+            obj(&mut ppu, i, 16, 8 + 12 * i as u8, 1, 0);
+        }
+        frame(&mut ppu, LCDC_OBJ);
+        for i in 1..10 {
+            assert_eq!(shade_at(&ppu, 12 * i, 0), 3, "object {}", i);
+        }
+        assert_eq!(shade_at(&ppu, 120, 0), 0);
+        assert_eq!(shade_at(&ppu, 12, 8), 0);
+    }
+
+    #[test]
+    fn x_then_oam_priority() {
+        let mut ppu: Ppu = ppu();
+        tile(&mut ppu, 1, 0xFF, 0x00);
+        tile(&mut ppu, 2, 0x00, 0xFF);
+        // the smaller X wins the overlap, whatever the OAM order
+        obj(&mut ppu, 0, 16, 12, 1, 0);
+        obj(&mut ppu, 1, 16, 10, 2, 0);
+        // on the same X the earlier OAM entry wins
+        obj(&mut ppu, 2, 24, 10, 1, 0);
+        obj(&mut ppu, 3, 24, 10, 2, 0);
+        frame(&mut ppu, LCDC_OBJ);
+        assert_eq!(shade_at(&ppu, 2, 0), 2);
+        assert_eq!(shade_at(&ppu, 9, 0), 2);
+        assert_eq!(shade_at(&ppu, 10, 0), 1);
+        assert_eq!(shade_at(&ppu, 2, 8), 1);
+    }
+
+    #[test]
+    fn tall_objects_flip() {
+        let mut ppu: Ppu = ppu();
+        // colour 3 down the left column of the top tile, colour 2 below it
+        tile(&mut ppu, 2, 0xFF, 0x80);
+        tile(&mut ppu, 3, 0x00, 0xFF);
+        // bit 0 of the tile number is ignored
+        obj(&mut ppu, 0, 16, 8, 3, 0x00);
+        obj(&mut ppu, 1, 16, 24, 2, 0x40);
+        obj(&mut ppu, 2, 16, 40, 2, 0x60);
+        frame(&mut ppu, LCDC_OBJ | 0x04);
+        assert_eq!(
+            [(0, 0), (1, 0), (0, 8)].map(|(x, y)| shade_at(&ppu, x, y)),
+            [3, 1, 2]
+        );
+        assert_eq!(
+            [(16, 0), (16, 8), (17, 15)].map(|(x, y)| shade_at(&ppu, x, y)),
+            [2, 3, 1]
+        );
+        assert_eq!(
+            [(32, 0), (39, 8), (32, 8)].map(|(x, y)| shade_at(&ppu, x, y)),
+            [2, 3, 1]
+        );
+    }
+
+    #[test]
+    fn behind_bg() {
+        let mut ppu: Ppu = ppu();
+        tile(&mut ppu, 1, 0x00, 0xFF);
+        tile(&mut ppu, 5, 0xFF, 0x00);
+        ppu.write_byte(0x9800, 5);
+        ppu.write_byte(0x9820, 5);
+        // only BG colour 0 lets a background object through
+        obj(&mut ppu, 0, 16, 8, 1, 0x80);
+        obj(&mut ppu, 1, 16, 16, 1, 0x80);
+        obj(&mut ppu, 2, 24, 8, 1, 0x00);
+        frame(&mut ppu, LCDC_OBJ);
+        assert_eq!(shade_at(&ppu, 0, 0), 1);
+        assert_eq!(shade_at(&ppu, 8, 0), 2);
+        assert_eq!(shade_at(&ppu, 0, 8), 2);
+    }
+
+    const LCDC_WIN: u8 = 0xF1; // LCD, window from 0x9C00, 0x8000 tiles and BG on
+
+    /// Fills window map rows `rows` with `tile`.
+    fn window_map(ppu: &mut Ppu, rows: std::ops::Range<u16>, tile: u8) {
+        for addr in 0x9C00 + rows.start * 0x20..0x9C00 + rows.end * 0x20 {
+            ppu.write_byte(addr, tile);
+        }
+    }
+
+    #[test]
+    fn window_line_counter() {
+        let mut ppu: Ppu = ppu();
+        tile(&mut ppu, 6, 0xFF, 0x00);
+        tile(&mut ppu, 7, 0x00, 0xFF);
+        window_map(&mut ppu, 0..1, 6);
+        window_map(&mut ppu, 1..32, 7);
+        ppu.write_byte(WX, 7);
+        // hide the window on lines 4 to 11
+        ppu.write_byte(LCDC, LCDC_WIN);
+        lines(&mut ppu, 4);
+        ppu.write_byte(LCDC, LCDC_WIN & !0x20);
+        lines(&mut ppu, 8);
+        ppu.write_byte(LCDC, LCDC_WIN);
+        lines(&mut ppu, 142);
+        assert_eq!(shade_at(&ppu, 0, 3), 1);
+        assert_eq!(shade_at(&ppu, 0, 4), 0);
+        // line 12 draws window line 4, not 12
+        assert_eq!(shade_at(&ppu, 0, 12), 1);
+        assert_eq!(shade_at(&ppu, 0, 15), 1);
+        assert_eq!(shade_at(&ppu, 0, 16), 2);
+    }
+
+    #[test]
+    fn window_left_of_screen() {
+        let mut ppu: Ppu = ppu();
+        // colour 1 in the right half of each window tile
+        tile(&mut ppu, 8, 0x0F, 0x00);
+        window_map(&mut ppu, 0..32, 8);
+        ppu.write_byte(WX, 3);
+        frame(&mut ppu, LCDC_WIN);
+        assert_eq!(
+            [0, 3, 4, 7, 8].map(|x| shade_at(&ppu, x, 0)),
+            [1, 1, 0, 0, 1]
+        );
+    }
+
+    #[test]
+    fn window_at_166() {
+        let mut ppu: Ppu = ppu();
+        tile(&mut ppu, 6, 0xFF, 0x00);
+        window_map(&mut ppu, 0..32, 6);
+        ppu.write_byte(WX, 166);
+        frame(&mut ppu, LCDC_WIN);
+        // hidden on its own line, then covering the whole of the next
+        assert_eq!(shade_at(&ppu, 159, 0), 0);
+        assert_eq!((shade_at(&ppu, 0, 1), shade_at(&ppu, 159, 1)), (1, 1));
+        assert_eq!(shade_at(&ppu, 0, 2), 0);
+    }
+
+    /// Dots line 0 spends in Mode 3.
+    fn mode3_dots(ppu: &mut Ppu, lcdc: u8) -> u16 {
+        ppu.write_byte(LCDC, lcdc);
+        while ppu.ppu_mode != Mode0 {
+            ppu.dot();
+        }
+        ppu.dots - 80
+    }
+
+    #[test]
+    fn mode3_length() {
+        assert_eq!(mode3_dots(&mut ppu(), LCDC_OBJ), 172);
+        // a dot for each pixel dropped by fine scroll
+        for scx in 1..8 {
+            let mut ppu: Ppu = ppu();
+            ppu.write_byte(SCX, scx);
+            assert_eq!(mode3_dots(&mut ppu, LCDC_OBJ), 172 + scx as u16);
+        }
+        // six to restart the fetcher on the window
+        for wx in [7, 8, 86] {
+            let mut ppu: Ppu = ppu();
+            ppu.write_byte(WX, wx);
+            assert_eq!(mode3_dots(&mut ppu, LCDC_WIN), 178, "WX {}", wx);
+        }
+    }
+
+    #[test]
+    fn object_stalls() {
+        // six dots for the fetch, plus what is left of the BG tile fetch under the
+        // object's left edge
+        for (x, dots) in [
+            (0, 11),
+            (8, 11),
+            (9, 10),
+            (12, 7),
+            (13, 6),
+            (15, 6),
+            (16, 11),
+        ] {
+            let mut ppu: Ppu = ppu();
+            obj(&mut ppu, 0, 16, x, 0, 0);
+            assert_eq!(mode3_dots(&mut ppu, LCDC_OBJ), 172 + dots, "X {}", x);
+        }
+        // SCX moves the tile edges
+        let mut scrolled: Ppu = ppu();
+        scrolled.write_byte(SCX, 3);
+        obj(&mut scrolled, 0, 16, 8, 0, 0);
+        assert_eq!(mode3_dots(&mut scrolled, LCDC_OBJ), 175 + 8);
+        // a second object on the same tile only waits for its own fetch
+        let mut pair: Ppu = ppu();
+        obj(&mut pair, 0, 16, 8, 0, 0);
+        obj(&mut pair, 1, 16, 8, 0, 0);
+        assert_eq!(mode3_dots(&mut pair, LCDC_OBJ), 172 + 11 + 6);
+    }
+
+    fn finish_line(ppu: &mut Ppu) {
+        ppu.dot();
+        while ppu.dots != 0 {
+            ppu.dot();
+        }
+    }
+
+    /// Renders the current line up to pixel `lx`.
+    fn draw_to(ppu: &mut Ppu, lcdc: u8, lx: u8) {
+        ppu.write_byte(LCDC, lcdc);
+        while ppu.ppu_mode != Mode3 || ppu.lx < lx {
+            ppu.dot();
+        }
+    }
+
+    #[test]
+    fn mid_line_writes() {
+        // BG columns alternate between colours 1 and 2
+        let mut ppu: Ppu = ppu();
+        tile(&mut ppu, 1, 0xFF, 0x00);
+        tile(&mut ppu, 2, 0x00, 0xFF);
+        for col in 0..32 {
+            ppu.write_byte(0x9800 + col, 1 + (col as u8 & 1));
+        }
+        // palettes apply from the next pixel out
+        draw_to(&mut ppu, LCDC_OBJ, 80);
+        ppu.write_byte(BGP, 0xFF);
+        finish_line(&mut ppu);
+        assert_eq!((shade_at(&ppu, 79, 0), shade_at(&ppu, 80, 0)), (2, 3));
+        ppu.write_byte(BGP, 0xE4);
+
+        // as does LCDC bit 0 blanking the BG
+        draw_to(&mut ppu, LCDC_OBJ, 80);
+        ppu.write_byte(LCDC, LCDC_OBJ & !0x01);
+        finish_line(&mut ppu);
+        assert_eq!((shade_at(&ppu, 1, 1), shade_at(&ppu, 80, 1)), (1, 0));
+        ppu.write_byte(LCDC, LCDC_OBJ);
+
+        // SCX is read for each tile fetch, and the tile at pixel 80 is already fetched
+        draw_to(&mut ppu, LCDC_OBJ, 80);
+        ppu.write_byte(SCX, 8);
+        finish_line(&mut ppu);
+        assert_eq!(
+            [79, 80, 87, 88, 95].map(|x| shade_at(&ppu, x, 2)),
+            [2, 1, 1, 1, 1]
+        );
+    }
+}
@@ -0,0 +1,45 @@
+use crate::mmu::Mmu;
+use std::path::PathBuf;
+
+/// Frames between autosaves, roughly five seconds.
+pub const AUTOSAVE_FRAMES: u32 = 300;
+
+/// A raw `.sav` file next to the ROM, holding the cartridge's battery-backed RAM.
+pub struct SaveFile {
+    path: PathBuf,
+    last: Option<Vec<u8>>,
+}
+
+impl SaveFile {
+    pub fn new(rom: &str) -> Self {
+        Self {
+            path: PathBuf::from(rom).with_extension("sav"),
+            last: None,
+        }
+    }
+
+    pub fn load(&mut self, mmu: &mut Mmu) {
+        match std::fs::read(&self.path) {
+            Ok(data) => {
+                mmu.load_save(&data);
+                self.last = Some(data);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => eprintln!("FAILED TO READ {}: {}", self.path.display(), e),
+        }
+    }
+
+    /// Writes the save out if it changed since it was last loaded or written.
+    pub fn flush(&mut self, mmu: &Mmu) {
+        let Some(data) = mmu.save_data() else {
+            return;
+        };
+        if self.last.as_ref() == Some(&data) {
+            return;
+        }
+        match std::fs::write(&self.path, &data) {
+            Ok(_) => self.last = Some(data),
+            Err(e) => eprintln!("FAILED TO WRITE {}: {}", self.path.display(), e),
+        }
+    }
+}
@@ -0,0 +1,514 @@
+use crate::ppu::rgb555;
+use crate::utils::*;
+
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+
+// where the game screen sits inside the border
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+// the screen in 8x8 cells, each with its own palette
+const CELLS_X: usize = 20;
+const CELLS_Y: usize = 18;
+// bytes the SNES copies out of the screen for a *_TRN command
+const TRANSFER_SIZE: usize = 0x1000;
+
+const DEFAULT_PALETTE: [u16; 4] = [
+    rgb555(248, 232, 200),
+    rgb555(216, 144, 72),
+    rgb555(168, 40, 32),
+    rgb555(48, 24, 80),
+];
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Transfer {
+    Palettes,     // PAL_TRN: 512 system palettes
+    Tiles(usize), // CHR_TRN: border tiles 0x00-0x7F or 0x80-0xFF
+    Border,       // PCT_TRN: border map and palettes 4-7
+}
+
+/// The Super Game Boy, which listens for command packets sent by pulsing P14 and P15
+/// and draws the screen colourised inside a 256x224 border.
+pub struct Sgb {
+    select: u8,          // the last P14/P15 written
+    bits: Option<usize>, // bits received into the current packet, None when idle
+    packet: [u8; 16],
+    command: Vec<u8>, // the packets of a multi-packet command so far
+    players: u8,      // 1, 2 or 4 after MLT_REQ
+    player: u8,       // whose joypad P1 reads
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<[u16; 4]>,
+    attrs: [u8; CELLS_X * CELLS_Y],
+    mask: u8,             // MASK_EN: 0 off, 1 freeze, 2 black, 3 colour 0
+    frozen: Vec<u8>,      // shades kept while the screen is frozen
+    tiles: Vec<u8>,       // 256 SNES 4bpp border tiles
+    border_map: Vec<u16>, // 32x28 tile entries
+    border_palettes: [[u16; 16]; 4],
+    transfer: Option<Transfer>,
+}
+
+impl Default for Sgb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        Self {
+            select: 0x30,
+            bits: None,
+            packet: [0; 16],
+            command: Vec::with_capacity(16 * 7),
+            players: 1,
+            player: 0,
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![DEFAULT_PALETTE; 512],
+            attrs: [0; CELLS_X * CELLS_Y],
+            mask: 0,
+            frozen: vec![0; 160 * 144],
+            tiles: vec![0; 256 * 32],
+            border_map: vec![0; 32 * 28],
+            border_palettes: [[0; 16]; 4],
+            transfer: None,
+        }
+    }
+
+    /// Packets start with both lines low, then each bit is a pulse on P14 (0) or
+    /// P15 (1) followed by both lines high. 128 bits and a 0 stop bit make a packet.
+    pub fn write_p1(&mut self, b: u8) {
+        let select: u8 = b & 0x30;
+        match select {
+            0x00 => {
+                self.bits = Some(0);
+                self.packet = [0; 16];
+            }
+            0x10 | 0x20 if self.select == 0x30 => {
+                if let Some(bits) = self.bits {
+                    self.receive(bits, select == 0x10);
+                }
+            }
+            // with MLT_REQ on, P15 going high moves on to the next joypad
+            0x30 if self.bits.is_none() && !bit(self.select, 5) && self.players > 1 => {
+                self.player = (self.player + 1) & (self.players - 1);
+            }
+            _ => (),
+        }
+        self.select = select;
+    }
+
+    /// With both lines high, P1 reads back the current joypad as 0xF minus its number.
+    /// Only the first joypad has any buttons pressed.
+    pub fn read_p1(&self, p1: u8) -> u8 {
+        if self.players == 1 {
+            return p1;
+        }
+        match p1 & 0x30 {
+            0x30 => (p1 & 0xF0) | (0x0F - self.player),
+            _ if self.player != 0 => p1 | 0x0F,
+            _ => p1,
+        }
+    }
+
+    /// Takes the shades of a finished frame, grabbing any pending VRAM transfer, and
+    /// returns the 256x224 picture in RGB555.
+    pub fn frame(&mut self, shades: &[u8]) -> Vec<u16> {
+        if let Some(transfer) = self.transfer.take() {
+            self.vram_transfer(transfer, &screen_data(shades));
+        }
+        let shades: &[u8] = if self.mask == 1 {
+            &self.frozen
+        } else {
+            self.frozen.copy_from_slice(shades);
+            shades
+        };
+        let backdrop: u16 = self.palettes[0][0];
+        let mut frame: Vec<u16> = vec![backdrop; SGB_WIDTH * SGB_HEIGHT];
+        for y in 0..144 {
+            for x in 0..160 {
+                let palette: usize = self.attrs[(y / 8) * CELLS_X + x / 8] as usize;
+                frame[(y + SCREEN_Y) * SGB_WIDTH + x + SCREEN_X] = match self.mask {
+                    2 => 0x0000,
+                    3 => backdrop,
+                    _ => self.palettes[palette][shades[y * 160 + x] as usize],
+                };
+            }
+        }
+        self.draw_border(&mut frame);
+        frame
+    }
+
+    fn receive(&mut self, bits: usize, one: bool) {
+        if bits == 128 {
+            // the stop bit has to be 0, otherwise the packet is dropped
+            self.bits = None;
+            if !one {
+                self.command.extend_from_slice(&self.packet);
+                let packets: usize = (self.command[0] & 0x07).max(1) as usize;
+                if self.command.len() >= packets * 16 {
+                    let command: Vec<u8> = std::mem::take(&mut self.command);
+                    self.run(&command);
+                }
+            }
+            return;
+        }
+        if one {
+            self.packet[bits / 8] |= 1 << (bits % 8);
+        }
+        self.bits = Some(bits + 1);
+    }
+
+    fn run(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            0x00 => self.set_palettes(0, 1, data),
+            0x01 => self.set_palettes(2, 3, data),
+            0x02 => self.set_palettes(0, 3, data),
+            0x03 => self.set_palettes(1, 2, data),
+            0x04 => self.attr_blk(data),
+            0x05 => self.attr_lin(data),
+            0x06 => self.attr_div(data),
+            0x07 => self.attr_chr(data),
+            0x0A => {
+                // PAL_SET: four system palettes by number
+                for i in 0..4 {
+                    let n: usize = word(data, 1 + i * 2) as usize & 0x1FF;
+                    self.palettes[i] = self.system_palettes[n];
+                }
+                if bit(data[9], 6) {
+                    self.mask = 0;
+                }
+            }
+            0x0B => self.transfer = Some(Transfer::Palettes),
+            0x11 => {
+                self.players = match data[1] & 0x03 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            }
+            0x13 => self.transfer = Some(Transfer::Tiles((data[1] & 1) as usize)),
+            0x14 => self.transfer = Some(Transfer::Border),
+            0x17 => self.mask = data[1] & 0x03,
+            _ => (),
+        }
+    }
+
+    /// PAL01-PAL12: a shared colour 0, then colours 1-3 of two palettes.
+    fn set_palettes(&mut self, a: usize, b: usize, data: &[u8]) {
+        let colour0: u16 = colour(data, 1);
+        for palette in self.palettes.iter_mut() {
+            palette[0] = colour0;
+        }
+        for i in 0..3 {
+            self.palettes[a][i + 1] = colour(data, 3 + i * 2);
+            self.palettes[b][i + 1] = colour(data, 9 + i * 2);
+        }
+    }
+
+    /// ATTR_BLK: rectangles that colour their inside, border and outside separately.
+    fn attr_blk(&mut self, data: &[u8]) {
+        let sets: usize = (data[1] & 0x1F) as usize;
+        for set in data[2..].chunks_exact(6).take(sets) {
+            let control: u8 = set[0] & 0x07;
+            let inside: u8 = set[1] & 0x03;
+            let outside: u8 = set[1] >> 4 & 0x03;
+            // changing only the inside or only the outside takes the border with it
+            let border: u8 = match control {
+                1 => inside,
+                4 => outside,
+                _ => set[1] >> 2 & 0x03,
+            };
+            let (x1, y1, x2, y2) = (set[2] & 0x1F, set[3] & 0x1F, set[4] & 0x1F, set[5] & 0x1F);
+            for y in 0..CELLS_Y as u8 {
+                for x in 0..CELLS_X as u8 {
+                    let within: bool = (x1..=x2).contains(&x) && (y1..=y2).contains(&y);
+                    let edge: bool = within && (x == x1 || x == x2 || y == y1 || y == y2);
+                    let palette: Option<u8> = match (within, edge) {
+                        (true, false) if bit(control, 0) => Some(inside),
+                        (true, true) if bit(control, 1) || control == 1 || control == 4 => {
+                            Some(border)
+                        }
+                        (false, _) if bit(control, 2) => Some(outside),
+                        _ => None,
+                    };
+                    if let Some(palette) = palette {
+                        self.attrs[y as usize * CELLS_X + x as usize] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    /// ATTR_LIN: whole rows (bit 7 set) or columns of cells.
+    fn attr_lin(&mut self, data: &[u8]) {
+        let lines: usize = data[1] as usize;
+        for &line in data[2..].iter().take(lines) {
+            let n: usize = (line & 0x1F) as usize;
+            let palette: u8 = line >> 5 & 0x03;
+            if bit(line, 7) {
+                if n < CELLS_Y {
+                    self.attrs[n * CELLS_X..(n + 1) * CELLS_X].fill(palette);
+                }
+            } else if n < CELLS_X {
+                for y in 0..CELLS_Y {
+                    self.attrs[y * CELLS_X + n] = palette;
+                }
+            }
+        }
+    }
+
+    /// ATTR_DIV: splits the screen at a row (bit 6 set) or column.
+    fn attr_div(&mut self, data: &[u8]) {
+        let after: u8 = data[1] & 0x03;
+        let before: u8 = data[1] >> 2 & 0x03;
+        let on: u8 = data[1] >> 4 & 0x03;
+        let at: usize = (data[2] & 0x1F) as usize;
+        for y in 0..CELLS_Y {
+            for x in 0..CELLS_X {
+                let n: usize = if bit(data[1], 6) { y } else { x };
+                self.attrs[y * CELLS_X + x] = match n.cmp(&at) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on,
+                    std::cmp::Ordering::Greater => after,
+                };
+            }
+        }
+    }
+
+    /// ATTR_CHR: one palette per cell, four to a byte, running across or down.
+    fn attr_chr(&mut self, data: &[u8]) {
+        let (mut x, mut y) = (
+            (data[1] as usize).min(CELLS_X - 1),
+            (data[2] as usize).min(CELLS_Y - 1),
+        );
+        let count: usize = (word(data, 3) as usize).min(CELLS_X * CELLS_Y);
+        let down: bool = data[5] == 1;
+        for i in 0..count {
+            let Some(&b) = data.get(6 + i / 4) else {
+                break;
+            };
+            self.attrs[y * CELLS_X + x] = b >> (6 - (i % 4) * 2) & 0x03;
+            if down {
+                y += 1;
+                if y == CELLS_Y {
+                    y = 0;
+                    x = (x + 1) % CELLS_X;
+                }
+            } else {
+                x += 1;
+                if x == CELLS_X {
+                    x = 0;
+                    y = (y + 1) % CELLS_Y;
+                }
+            }
+        }
+    }
+
+    fn vram_transfer(&mut self, transfer: Transfer, data: &[u8]) {
+        match transfer {
+            Transfer::Palettes => {
+                for (n, palette) in self.system_palettes.iter_mut().enumerate() {
+                    for (i, c) in palette.iter_mut().enumerate() {
+                        *c = colour(data, n * 8 + i * 2);
+                    }
+                }
+            }
+            Transfer::Tiles(half) => {
+                self.tiles[half * TRANSFER_SIZE..(half + 1) * TRANSFER_SIZE].copy_from_slice(data)
+            }
+            Transfer::Border => {
+                for (i, entry) in self.border_map.iter_mut().enumerate() {
+                    *entry = word(data, i * 2);
+                }
+                for (n, palette) in self.border_palettes.iter_mut().enumerate() {
+                    for (i, c) in palette.iter_mut().enumerate() {
+                        *c = colour(data, 0x800 + n * 32 + i * 2);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Draws the border over the frame. Colour 0 is see-through, which is what leaves
+    /// the game screen visible.
+    fn draw_border(&self, frame: &mut [u16]) {
+        for (i, &entry) in self.border_map.iter().enumerate() {
+            let tile: &[u8] = &self.tiles[(entry & 0xFF) as usize * 32..][..32];
+            let palette: &[u16; 16] = &self.border_palettes[(entry >> 10 & 0x03) as usize];
+            for row in 0..8 {
+                let ty: usize = if bit(entry, 15) { 7 - row } else { row };
+                for col in 0..8 {
+                    let tx: u8 = if bit(entry, 14) { col } else { 7 - col };
+                    // SNES 4bpp: planes 0 and 1 in the first 16 bytes, 2 and 3 after
+                    let colour: usize = bit(tile[ty * 2], tx) as usize
+                        | (bit(tile[ty * 2 + 1], tx) as usize) << 1
+                        | (bit(tile[16 + ty * 2], tx) as usize) << 2
+                        | (bit(tile[16 + ty * 2 + 1], tx) as usize) << 3;
+                    if colour != 0 {
+                        let (x, y) = ((i % 32) * 8 + col as usize, (i / 32) * 8 + row);
+                        frame[y * SGB_WIDTH + x] = palette[colour];
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn word(data: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([data[i], data[i + 1]])
+}
+
+fn colour(data: &[u8], i: usize) -> u16 {
+    word(data, i) & 0x7FFF
+}
+
+/// The SNES reads transfers off the screen: the first 256 tiles, left to right and top
+/// to bottom, turned back into 2bpp tile data.
+fn screen_data(shades: &[u8]) -> Vec<u8> {
+    let mut data: Vec<u8> = vec![0; TRANSFER_SIZE];
+    for tile in 0..256 {
+        let (tx, ty) = ((tile % CELLS_X) * 8, (tile / CELLS_X) * 8);
+        for row in 0..8 {
+            let (mut lo, mut hi) = (0u8, 0u8);
+            for col in 0..8 {
+                let shade: u8 = shades[(ty + row) * 160 + tx + col];
+                lo |= (shade & 1) << (7 - col);
+                hi |= (shade >> 1 & 1) << (7 - col);
+            }
+            data[tile * 16 + row * 2] = lo;
+            data[tile * 16 + row * 2 + 1] = hi;
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pulse(sgb: &mut Sgb, select: u8) {
+        sgb.write_p1(select);
+        sgb.write_p1(0x30);
+    }
+
+    /// Sends the bits of `data` LSB first after a reset pulse, then the stop bit.
+    fn send_bits(sgb: &mut Sgb, data: &[u8], stop: bool) {
+        pulse(sgb, 0x00);
+        for i in 0..data.len() * 8 {
+            let one: bool = bit(data[i / 8], (i % 8) as u8);
+            pulse(sgb, if one { 0x10 } else { 0x20 });
+        }
+        pulse(sgb, if stop { 0x10 } else { 0x20 });
+    }
+
+    /// Sends `command` as whole packets, padding the last with zeros.
+    fn send(sgb: &mut Sgb, command: &[u8]) {
+        for packet in command.chunks(16) {
+            let mut data: [u8; 16] = [0; 16];
+            data[..packet.len()].copy_from_slice(packet);
+            send_bits(sgb, &data, false);
+        }
+    }
+
+    /// PAL01 with colour 0 shared, palette 0 colours 1-3 and palette 1 colours 1-3.
+    fn pal01(colours: [u16; 7]) -> Vec<u8> {
+        let mut data: Vec<u8> = vec![0x01];
+        data.extend(colours.iter().flat_map(|c| c.to_le_bytes()));
+        data
+    }
+
+    fn pixel(frame: &[u16], x: usize, y: usize) -> u16 {
+        frame[(y + SCREEN_Y) * SGB_WIDTH + x + SCREEN_X]
+    }
+
+    #[test]
+    fn packet_bits() {
+        let mut sgb: Sgb = Sgb::new();
+        let command: Vec<u8> = pal01([0x7FFF, 0x0001, 0x0002, 0x0003, 0x0100, 0x0200, 0x0300]);
+        send(&mut sgb, &command);
+        assert_eq!(sgb.palettes[0], [0x7FFF, 0x0001, 0x0002, 0x0003]);
+        assert_eq!(sgb.palettes[1], [0x7FFF, 0x0100, 0x0200, 0x0300]);
+        assert_eq!(sgb.palettes[2][0], 0x7FFF);
+
+        // a stop bit of 1 drops the packet
+        let mut data: [u8; 16] = [0; 16];
+        data[..command.len()].copy_from_slice(&pal01([0; 7]));
+        send_bits(&mut sgb, &data, true);
+        assert_eq!(sgb.palettes[0][1], 0x0001);
+        // a reset pulse part way through starts the packet over
+        send_bits(&mut sgb, &data[..8], false);
+        send(&mut sgb, &data);
+        assert_eq!(sgb.palettes[0], [0; 4]);
+    }
+
+    #[test]
+    fn multi_packet() {
+        let mut sgb: Sgb = Sgb::new();
+        // ATTR_BLK over two packets: the third set starts in the second one
+        let mut command: Vec<u8> = vec![0x22, 3];
+        command.extend([0x01, 0x01, 0, 0, 0, 0]);
+        command.extend([0x01, 0x02, 1, 0, 1, 0]);
+        command.extend([0x01, 0x03, 2, 0, 2, 0]);
+        send(&mut sgb, &command[..16]);
+        assert_eq!(sgb.attrs[..3], [0, 0, 0]);
+        send(&mut sgb, &command[16..]);
+        assert_eq!(sgb.attrs[..4], [1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn attributes_colour_the_screen() {
+        let mut sgb: Sgb = Sgb::new();
+        send(&mut sgb, &pal01([0, 0x0011, 0, 0, 0x0022, 0, 0]));
+        // palette 1 inside cells (2,2)-(4,4), which takes its border with it
+        send(&mut sgb, &[0x21, 1, 0x01, 0x01, 2, 2, 4, 4]);
+        let frame: Vec<u16> = sgb.frame(&[1; 160 * 144]);
+        assert_eq!(pixel(&frame, 0, 0), 0x0011);
+        assert_eq!(pixel(&frame, 16, 16), 0x0022);
+        assert_eq!(pixel(&frame, 39, 39), 0x0022);
+        assert_eq!(pixel(&frame, 40, 39), 0x0011);
+        // outside and border alone
+        send(&mut sgb, &[0x21, 1, 0x06, 0x10, 2, 2, 4, 4]);
+        let frame: Vec<u16> = sgb.frame(&[1; 160 * 144]);
+        assert_eq!(pixel(&frame, 0, 0), 0x0022);
+        assert_eq!(pixel(&frame, 16, 16), 0x0011);
+        assert_eq!(pixel(&frame, 24, 24), 0x0022);
+    }
+
+    #[test]
+    fn joypad_ids() {
+        let mut sgb: Sgb = Sgb::new();
+        assert_eq!(sgb.read_p1(0xFF), 0xFF);
+        // MLT_REQ for four players, then P15 going high moves to the next joypad
+        send(&mut sgb, &[0x89, 0x03]);
+        let mut ids: Vec<u8> = Vec::new();
+        for _ in 0..5 {
+            ids.push(sgb.read_p1(0xFF) & 0x0F);
+            pulse(&mut sgb, 0x10);
+        }
+        assert_eq!(ids, [0xF, 0xE, 0xD, 0xC, 0xF]);
+        // only the first joypad has buttons
+        assert_eq!(sgb.read_p1(0xE0), 0xEF);
+        for _ in 0..3 {
+            pulse(&mut sgb, 0x10);
+        }
+        assert_eq!(sgb.read_p1(0xE0), 0xE0);
+        send(&mut sgb, &[0x89, 0x00]);
+        assert_eq!(sgb.read_p1(0xFF), 0xFF);
+    }
+
+    #[test]
+    fn mask_freezes() {
+        let mut sgb: Sgb = Sgb::new();
+        let before: Vec<u16> = sgb.frame(&[1; 160 * 144]);
+        send(&mut sgb, &[0xB9, 0x01]);
+        assert_eq!(sgb.frame(&[3; 160 * 144]), before);
+        // black, then colour 0
+        send(&mut sgb, &[0xB9, 0x02]);
+        assert_eq!(pixel(&sgb.frame(&[3; 160 * 144]), 0, 0), 0x0000);
+        send(&mut sgb, &[0xB9, 0x03]);
+        assert_eq!(pixel(&sgb.frame(&[3; 160 * 144]), 0, 0), DEFAULT_PALETTE[0]);
+        send(&mut sgb, &[0xB9, 0x00]);
+        assert_eq!(pixel(&sgb.frame(&[3; 160 * 144]), 0, 0), DEFAULT_PALETTE[3]);
+    }
+}
@@ -1,11 +1,27 @@
 use std::ops::{BitOr, Shl};
 
+pub const P1: u16 = 0xFF00;
 pub const SB: u16 = 0xFF01;
 pub const SC: u16 = 0xFF02;
 pub const DIV: u16 = 0xFF04;
 pub const TIMA: u16 = 0xFF05;
 pub const TMA: u16 = 0xFF06;
 pub const TAC: u16 = 0xFF07;
+pub const NR10: u16 = 0xFF10;
+pub const NR11: u16 = 0xFF11;
+pub const NR12: u16 = 0xFF12;
+pub const NR14: u16 = 0xFF14;
+pub const NR21: u16 = 0xFF16;
+pub const NR24: u16 = 0xFF19;
+pub const NR30: u16 = 0xFF1A;
+pub const NR31: u16 = 0xFF1B;
+pub const NR32: u16 = 0xFF1C;
+pub const NR34: u16 = 0xFF1E;
+pub const NR41: u16 = 0xFF20;
+pub const NR44: u16 = 0xFF23;
+pub const NR50: u16 = 0xFF24;
+pub const NR51: u16 = 0xFF25;
+pub const NR52: u16 = 0xFF26;
 pub const LCDC: u16 = 0xFF40;
 pub const STAT: u16 = 0xFF41;
 pub const SCY: u16 = 0xFF42;
@@ -18,6 +34,21 @@ pub const OBP0: u16 = 0xFF48;
 pub const OBP1: u16 = 0xFF49;
 pub const WY: u16 = 0xFF4A;
 pub const WX: u16 = 0xFF4B;
+pub const KEY0: u16 = 0xFF4C;
+pub const KEY1: u16 = 0xFF4D;
+pub const VBK: u16 = 0xFF4F;
+pub const BOOT: u16 = 0xFF50;
+pub const HDMA1: u16 = 0xFF51;
+pub const HDMA2: u16 = 0xFF52;
+pub const HDMA3: u16 = 0xFF53;
+pub const HDMA4: u16 = 0xFF54;
+pub const HDMA5: u16 = 0xFF55;
+pub const BCPS: u16 = 0xFF68;
+pub const BCPD: u16 = 0xFF69;
+pub const OCPS: u16 = 0xFF6A;
+pub const OCPD: u16 = 0xFF6B;
+pub const OPRI: u16 = 0xFF6C;
+pub const SVBK: u16 = 0xFF70;
 pub const IF: u16 = 0xFF0F;
 pub const IE: u16 = 0xFFFF;
 